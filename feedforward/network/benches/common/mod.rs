@@ -0,0 +1,19 @@
+//! Helpers shared by the benchmarks.
+
+use std::time::{Duration, Instant};
+
+/// Deterministic values which are not all the same.
+pub fn values<T : From<f32>>(len : usize, offset : usize) -> Vec<T> {
+    (0..len).map(|i| T::from((((i + offset) * 7919) % 1000) as f32 / 1000.0 - 0.5)).collect()
+}
+
+/// Runs the function repeatedly, returning the average time per run.
+pub fn time<F : FnMut()>(iterations : u32, mut function : F) -> Duration {
+    function();
+
+    let start = Instant::now();
+    for _ in 0..iterations {
+        function();
+    }
+    start.elapsed() / iterations
+}
@@ -0,0 +1,124 @@
+//! Compares the SIMD kernels against the scalar loops they replace, reporting both the speedup and
+//! the largest relative difference in the results, which should be at the level of rounding. Run
+//! with `cargo bench --features simd --bench kernels`.
+
+mod common;
+
+use std::hint::black_box;
+use std::time::Duration;
+
+use common::{time, values};
+use network::bench::{scalar, simd};
+
+/// A component-wise binary operation writing to an output slice.
+type Kernel<T> = fn(&[T], &[T], &mut [T]);
+
+/// The kernels for one precision, so that the scalar and SIMD versions can be compared in the
+/// same way for each.
+struct Kernels<T> {
+    dot : fn(&[T], &[T]) -> T,
+    axpy : fn(T, &[T], &mut [T]),
+    add : Kernel<T>,
+    sub : Kernel<T>,
+    mul : Kernel<T>,
+    scale : fn(T, &[T], &mut [T]),
+}
+
+/// The largest difference between corresponding values, relative to the size of the values.
+fn max_relative_difference<T : Copy + Into<f64>>(first : &[T], second : &[T]) -> f64 {
+    first
+    .iter()
+    .zip(second.iter())
+    .map(|(a, b)| {
+        let (a, b) : (f64, f64) = ((*a).into(), (*b).into());
+        (a - b).abs() / f64::max(1.0, a.abs())
+    })
+    .fold(0.0, f64::max)
+}
+
+fn compare(description : &str, scalar_time : Duration, simd_time : Duration, difference : f64) {
+    println!(
+        "{:<12} scalar {:>10.2?}   simd {:>10.2?}   speedup {:>5.2}x   max relative difference {:.2e}",
+        description,
+        scalar_time,
+        simd_time,
+        scalar_time.as_secs_f64() / simd_time.as_secs_f64(),
+        difference
+    );
+}
+
+/// Compares each of the scalar kernels against its SIMD version, for a range of slice lengths.
+fn compare_kernels<T : Copy + From<f32> + Into<f64>>(precision : &str, scalar : Kernels<T>, simd : Kernels<T>) {
+    let factor = T::from(0.3);
+
+    // Odd lengths exercise the scalar remainder after the last full register.
+    for len in [20, 784, 100_003] {
+        println!("{} length {}", precision, len);
+
+        let first = values::<T>(len, 0);
+        let second = values::<T>(len, 13);
+        let iterations = (10_000_000 / len) as u32;
+
+        compare(
+            "dot",
+            time(iterations, || { black_box((scalar.dot)(black_box(&first), black_box(&second))); }),
+            time(iterations, || { black_box((simd.dot)(black_box(&first), black_box(&second))); }),
+            max_relative_difference(&[(scalar.dot)(&first, &second)], &[(simd.dot)(&first, &second)])
+        );
+
+        let mut scalar_output = second.clone();
+        let mut simd_output = second.clone();
+        (scalar.axpy)(factor, &first, &mut scalar_output);
+        (simd.axpy)(factor, &first, &mut simd_output);
+        let difference = max_relative_difference(&scalar_output, &simd_output);
+        compare(
+            "axpy",
+            time(iterations, || (scalar.axpy)(factor, black_box(&first), black_box(&mut scalar_output))),
+            time(iterations, || (simd.axpy)(factor, black_box(&first), black_box(&mut simd_output))),
+            difference
+        );
+
+        let kernels : [(&str, Kernel<T>, Kernel<T>); 3] = [
+            ("add", scalar.add, simd.add),
+            ("sub", scalar.sub, simd.sub),
+            ("mul", scalar.mul, simd.mul),
+        ];
+        for (description, scalar_kernel, simd_kernel) in kernels {
+            scalar_kernel(&first, &second, &mut scalar_output);
+            simd_kernel(&first, &second, &mut simd_output);
+            let difference = max_relative_difference(&scalar_output, &simd_output);
+            compare(
+                description,
+                time(iterations, || scalar_kernel(black_box(&first), black_box(&second), black_box(&mut scalar_output))),
+                time(iterations, || simd_kernel(black_box(&first), black_box(&second), black_box(&mut simd_output))),
+                difference
+            );
+        }
+
+        (scalar.scale)(factor, &first, &mut scalar_output);
+        (simd.scale)(factor, &first, &mut simd_output);
+        let difference = max_relative_difference(&scalar_output, &simd_output);
+        compare(
+            "scale",
+            time(iterations, || (scalar.scale)(factor, black_box(&first), black_box(&mut scalar_output))),
+            time(iterations, || (simd.scale)(factor, black_box(&first), black_box(&mut simd_output))),
+            difference
+        );
+    }
+}
+
+fn main() {
+    println!("AVX2 and FMA {}", if simd::has_avx2_fma() { "detected" } else { "not detected, using SSE2" });
+
+    compare_kernels::<f64>(
+        "f64",
+        Kernels { dot : scalar::dot, axpy : scalar::axpy, add : scalar::add, sub : scalar::sub, mul : scalar::mul, scale : scalar::scale },
+        Kernels { dot : simd::double::dot, axpy : simd::double::axpy, add : simd::double::add, sub : simd::double::sub, mul : simd::double::mul, scale : simd::double::scale }
+    );
+
+    compare_kernels::<f32>(
+        "f32",
+        Kernels { dot : scalar::dot, axpy : scalar::axpy, add : scalar::add, sub : scalar::sub, mul : scalar::mul, scale : scalar::scale },
+        Kernels { dot : simd::single::dot, axpy : simd::single::axpy, add : simd::single::add, sub : simd::single::sub, mul : simd::single::mul, scale : simd::single::scale }
+    );
+}
@@ -0,0 +1,103 @@
+//! Compares the blocked matrix multiplication in `algebra` against the naive implementation it
+//! replaced, using the layer sizes of the MNIST network in `main.rs`.
+//! Run with `cargo bench --bench multiply`.
+
+mod common;
+
+use std::hint::black_box;
+use std::time::Duration;
+
+use common::{time, values};
+use network::algebra::Matrix;
+
+/// Creates a matrix of deterministic values.
+fn matrix(rows : usize, cols : usize) -> Matrix {
+    from_values(rows, cols, &values(rows * cols, 0))
+}
+
+/// Creates a matrix from its values in row-major order.
+fn from_values(rows : usize, cols : usize, values : &[f64]) -> Matrix {
+    let mut matrix = Matrix::zeros(rows, cols);
+    matrix.values_mut().copy_from_slice(values);
+    matrix
+}
+
+/// The original triple loop, indexing each element.
+fn naive_multiply(first : &Matrix, second : &Matrix) -> Matrix {
+    let mut unravelled = Vec::with_capacity(first.rows() * second.cols());
+
+    for i in 0..first.rows() {
+        for j in 0..second.cols() {
+            let mut new_val = 0.0;
+
+            for k in 0..first.cols() {
+                new_val += first.values()[i * first.cols() + k] * second.values()[k * second.cols() + j];
+            }
+
+            unravelled.push(new_val);
+        }
+    }
+
+    from_values(first.rows(), second.cols(), &unravelled)
+}
+
+fn compare(description : &str, iterations : u32, naive : Duration, blocked : Duration) {
+    println!(
+        "{:<40} naive {:>12.2?}   new {:>12.2?}   speedup {:>6.2}x   ({} iterations)",
+        description,
+        naive,
+        blocked,
+        naive.as_secs_f64() / blocked.as_secs_f64(),
+        iterations
+    );
+}
+
+fn main() {
+    // The first layer of the network, a 20x784 weight matrix, applied to a batch of 100 inputs
+    // stacked as columns.
+    let weights = matrix(20, 784);
+    let batch = matrix(784, 100);
+    let iterations = 200;
+    compare(
+        "matrix-matrix 20x784 * 784x100",
+        iterations,
+        time(iterations, || { black_box(naive_multiply(black_box(&weights), black_box(&batch))); }),
+        time(iterations, || { black_box(black_box(&weights) * black_box(&batch)); })
+    );
+
+    // The batched forward pass through the first layer, with 256 inputs as the rows of a matrix
+    // multiplied by the transpose of the weights.
+    let rows = matrix(256, 784);
+    let iterations = 200;
+    compare(
+        "matrix-transpose 256x784 * (20x784)^T",
+        iterations,
+        time(iterations, || {
+            for row in 0..256 {
+                let input = from_values(784, 1, rows.row(row));
+                black_box(naive_multiply(black_box(&weights), black_box(&input)));
+            }
+        }),
+        time(iterations, || { black_box(Matrix::multiply_transpose(black_box(&rows), black_box(&weights))); })
+    );
+
+    // Backpropagating through the first layer, a 1x20 row vector times the 20x784 weights.
+    let row = matrix(1, 20);
+    let iterations = 20_000;
+    compare(
+        "matrix-matrix 1x20 * 20x784",
+        iterations,
+        time(iterations, || { black_box(naive_multiply(black_box(&row), black_box(&weights))); }),
+        time(iterations, || { black_box(black_box(&row) * black_box(&weights)); })
+    );
+
+    // A large square product, where blocking matters most.
+    let square = matrix(784, 784);
+    let iterations = 3;
+    compare(
+        "matrix-matrix 784x784 * 784x784",
+        iterations,
+        time(iterations, || { black_box(naive_multiply(black_box(&square), black_box(&square))); }),
+        time(iterations, || { black_box(black_box(&square) * black_box(&square)); })
+    );
+}
@@ -0,0 +1,15 @@
+//! Links against a system BLAS library when the `blas` feature is enabled, finding it with
+//! pkg-config. OpenBLAS is preferred, falling back to any library providing the CBLAS interface.
+
+fn main() {
+    #[cfg(feature = "blas")]
+    {
+        let found = ["openblas", "cblas", "blas"]
+            .iter()
+            .any(|library| pkg_config::Config::new().probe(library).is_ok());
+
+        if !found {
+            panic!("The blas feature is enabled, but pkg-config could not find openblas, cblas or blas. Install OpenBLAS or set PKG_CONFIG_PATH to the directory containing its .pc file.");
+        }
+    }
+}
@@ -1,25 +1,359 @@
+use std::fmt;
+use std::str;
+use std::sync::Arc;
+
+use crate::error::NetworkError;
+use crate::float::Float;
+use crate::save::split_parameter;
+
 #[inline]
-pub fn sigmoid(x : f64) -> f64 {
+pub fn sigmoid<T : Float>(x : T) -> T {
     let y = (-x).exp();
     if y.is_infinite() {
-        0.0
+        T::ZERO
     }
     else {
-        1.0 / (1.0 + (-x).exp())
+        T::ONE / (T::ONE + (-x).exp())
     }
 }
 
 #[inline]
-pub fn sigmoid_derivative(x : f64) -> f64 {
-    sigmoid(x) * (1.0 - sigmoid(x))
+pub fn sigmoid_derivative<T : Float>(x : T) -> T {
+    sigmoid(x) * (T::ONE - sigmoid(x))
 }
 
 #[inline]
-pub fn swish(x : f64) -> f64 {
+pub fn swish<T : Float>(x : T) -> T {
     x * sigmoid(x) 
 }
 
 #[inline]
-pub fn swish_derivative(x : f64) -> f64 {
+pub fn swish_derivative<T : Float>(x : T) -> T {
     sigmoid(x) + x * sigmoid_derivative(x)
 }
+
+#[inline]
+pub fn linear<T : Float>(x : T) -> T {
+    x
+}
+
+#[inline]
+pub fn linear_derivative<T : Float>(_x : T) -> T {
+    T::ONE
+}
+
+/// Normalises the values into a probability distribution. The maximum value is subtracted before
+/// exponentiating, which leaves the result unchanged but prevents overflow.
+pub fn softmax<T : Float>(values : &[T]) -> Vec<T> {
+    let max = values.iter().cloned().fold(T::NEG_INFINITY, T::max);
+    let exps : Vec<T> = values.iter().map(|x| (*x - max).exp()).collect();
+    let sum : T = exps.iter().cloned().sum();
+
+    exps.iter().map(|x| *x / sum).collect()
+}
+
+/// Multiplies a gradient with respect to the outputs of softmax by its Jacobian, given the
+/// probabilities it output, to give the gradient with respect to its inputs.
+pub fn softmax_backward<T : Float>(probabilities : &[T], gradient : &[T]) -> Vec<T> {
+    let weighted_sum : T =
+        gradient
+        .iter()
+        .zip(probabilities.iter())
+        .map(|(g, p)| *g * *p)
+        .sum();
+
+    gradient
+    .iter()
+    .zip(probabilities.iter())
+    .map(|(g, p)| *p * (*g - weighted_sum))
+    .collect()
+}
+
+#[inline]
+pub fn relu<T : Float>(x : T) -> T {
+    x.max(T::ZERO)
+}
+
+#[inline]
+pub fn relu_derivative<T : Float>(x : T) -> T {
+    if x > T::ZERO { T::ONE } else { T::ZERO }
+}
+
+#[inline]
+pub fn leaky_relu<T : Float>(x : T, alpha : T) -> T {
+    if x > T::ZERO { x } else { alpha * x }
+}
+
+#[inline]
+pub fn leaky_relu_derivative<T : Float>(x : T, alpha : T) -> T {
+    if x > T::ZERO { T::ONE } else { alpha }
+}
+
+#[inline]
+pub fn elu<T : Float>(x : T, alpha : T) -> T {
+    if x > T::ZERO { x } else { alpha * x.exp_m1() }
+}
+
+#[inline]
+pub fn elu_derivative<T : Float>(x : T, alpha : T) -> T {
+    if x > T::ZERO { T::ONE } else { alpha * x.exp() }
+}
+
+const SELU_SCALE : f64 = 1.050_700_987_355_480_5;
+const SELU_ALPHA : f64 = 1.673_263_242_354_377_3;
+
+#[inline]
+pub fn selu<T : Float>(x : T) -> T {
+    T::from_f64(SELU_SCALE) * elu(x, T::from_f64(SELU_ALPHA))
+}
+
+#[inline]
+pub fn selu_derivative<T : Float>(x : T) -> T {
+    T::from_f64(SELU_SCALE) * elu_derivative(x, T::from_f64(SELU_ALPHA))
+}
+
+const GELU_COEFFICIENT : f64 = 0.044_715;
+
+/// Uses the tanh approximation of the Gaussian cumulative distribution function.
+#[inline]
+pub fn gelu<T : Float>(x : T) -> T {
+    let scale = T::from_f64((2.0 / std::f64::consts::PI).sqrt());
+    let inner = scale * (x + T::from_f64(GELU_COEFFICIENT) * x.powi(3));
+    T::from_f64(0.5) * x * (T::ONE + inner.tanh())
+}
+
+#[inline]
+pub fn gelu_derivative<T : Float>(x : T) -> T {
+    let (half, three) = (T::from_f64(0.5), T::from_f64(3.0));
+    let coefficient = T::from_f64(GELU_COEFFICIENT);
+    let scale = T::from_f64((2.0 / std::f64::consts::PI).sqrt());
+    let tanh = (scale * (x + coefficient * x.powi(3))).tanh();
+    half * (T::ONE + tanh) + half * x * (T::ONE - tanh * tanh) * scale * (T::ONE + three * coefficient * x * x)
+}
+
+#[inline]
+pub fn tanh<T : Float>(x : T) -> T {
+    x.tanh()
+}
+
+#[inline]
+pub fn tanh_derivative<T : Float>(x : T) -> T {
+    T::ONE - x.tanh().powi(2)
+}
+
+/// Written in a form that does not overflow for large inputs.
+#[inline]
+pub fn softplus<T : Float>(x : T) -> T {
+    x.max(T::ZERO) + (-x.abs()).exp().ln_1p()
+}
+
+#[inline]
+pub fn softplus_derivative<T : Float>(x : T) -> T {
+    sigmoid(x)
+}
+
+#[inline]
+pub fn mish<T : Float>(x : T) -> T {
+    x * softplus(x).tanh()
+}
+
+#[inline]
+pub fn mish_derivative<T : Float>(x : T) -> T {
+    let tanh = softplus(x).tanh();
+    tanh + x * sigmoid(x) * (T::ONE - tanh * tanh)
+}
+
+#[inline]
+pub fn hard_sigmoid<T : Float>(x : T) -> T {
+    (x / T::from_f64(6.0) + T::from_f64(0.5)).clamp(T::ZERO, T::ONE)
+}
+
+#[inline]
+pub fn hard_sigmoid_derivative<T : Float>(x : T) -> T {
+    let three = T::from_f64(3.0);
+    if x > -three && x < three { T::ONE / T::from_f64(6.0) } else { T::ZERO }
+}
+
+/// An activation function for a layer of the network, identified by name so that networks can be
+/// saved, compared and printed. Parameters are given in double precision whatever the precision of
+/// the network.
+#[derive(Clone)]
+pub enum Activation {
+    Relu,
+    LeakyRelu(f64),
+    Elu(f64),
+    Selu,
+    Gelu,
+    Tanh,
+    Softplus,
+    Mish,
+    HardSigmoid,
+    Linear,
+    Sigmoid,
+    Swish,
+    /// Normalises the whole layer into a probability distribution, so can only be used for the
+    /// output layer.
+    Softmax,
+    /// A user provided element-wise function and its derivative, identified by `name`. These are
+    /// evaluated in double precision, converting to and from the precision of the network, and
+    /// can be closures capturing their environment. They are most easily created with `custom`.
+    Custom {
+        name : &'static str,
+        function : Arc<dyn Fn(f64) -> f64 + Send + Sync>,
+        derivative : Arc<dyn Fn(f64) -> f64 + Send + Sync>,
+    },
+}
+
+impl Activation {
+    /// Creates a custom activation function from a function and its derivative, identified by
+    /// `name`.
+    pub fn custom<F, D>(name : &'static str, function : F, derivative : D) -> Activation
+        where F : Fn(f64) -> f64 + Send + Sync + 'static, D : Fn(f64) -> f64 + Send + Sync + 'static {
+        Activation::Custom {
+            name,
+            function : Arc::new(function),
+            derivative : Arc::new(derivative),
+        }
+    }
+
+    /// Applies the activation function to a single value. Softmax cannot be applied element-wise,
+    /// so use `activate` for layers which may have a softmax activation.
+    pub fn apply<T : Float>(&self, x : T) -> T {
+        match self {
+            Activation::Relu => relu(x),
+            Activation::LeakyRelu(alpha) => leaky_relu(x, T::from_f64(*alpha)),
+            Activation::Elu(alpha) => elu(x, T::from_f64(*alpha)),
+            Activation::Selu => selu(x),
+            Activation::Gelu => gelu(x),
+            Activation::Tanh => tanh(x),
+            Activation::Softplus => softplus(x),
+            Activation::Mish => mish(x),
+            Activation::HardSigmoid => hard_sigmoid(x),
+            Activation::Linear => linear(x),
+            Activation::Sigmoid => sigmoid(x),
+            Activation::Swish => swish(x),
+            Activation::Softmax => panic!("Attempt to apply softmax to a single value."),
+            Activation::Custom { function, .. } => T::from_f64(function(x.to_f64())),
+        }
+    }
+
+    /// Calculates the derivative of an element-wise activation function. The derivative of softmax
+    /// is not element-wise, so is handled by the loss function instead.
+    pub fn derivative<T : Float>(&self, x : T) -> T {
+        match self {
+            Activation::Relu => relu_derivative(x),
+            Activation::LeakyRelu(alpha) => leaky_relu_derivative(x, T::from_f64(*alpha)),
+            Activation::Elu(alpha) => elu_derivative(x, T::from_f64(*alpha)),
+            Activation::Selu => selu_derivative(x),
+            Activation::Gelu => gelu_derivative(x),
+            Activation::Tanh => tanh_derivative(x),
+            Activation::Softplus => softplus_derivative(x),
+            Activation::Mish => mish_derivative(x),
+            Activation::HardSigmoid => hard_sigmoid_derivative(x),
+            Activation::Linear => linear_derivative(x),
+            Activation::Sigmoid => sigmoid_derivative(x),
+            Activation::Swish => swish_derivative(x),
+            Activation::Softmax => panic!("Attempt to calculate the element-wise derivative of softmax."),
+            Activation::Custom { derivative, .. } => T::from_f64(derivative(x.to_f64())),
+        }
+    }
+
+    /// Applies the activation function to a whole layer.
+    pub fn activate<T : Float>(&self, values : &[T]) -> Vec<T> {
+        match self {
+            Activation::Softmax => softmax(values),
+            _ => values.iter().map(|x| self.apply(*x)).collect(),
+        }
+    }
+
+    /// Returns the name of the activation function.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Activation::Relu => "relu",
+            Activation::LeakyRelu(_) => "leaky_relu",
+            Activation::Elu(_) => "elu",
+            Activation::Selu => "selu",
+            Activation::Gelu => "gelu",
+            Activation::Tanh => "tanh",
+            Activation::Softplus => "softplus",
+            Activation::Mish => "mish",
+            Activation::HardSigmoid => "hard_sigmoid",
+            Activation::Linear => "linear",
+            Activation::Sigmoid => "sigmoid",
+            Activation::Swish => "swish",
+            Activation::Softmax => "softmax",
+            Activation::Custom { name, .. } => name,
+        }
+    }
+}
+
+/// Custom activations are compared by name, as functions cannot be compared.
+impl PartialEq for Activation {
+    fn eq(&self, other : &Activation) -> bool {
+        match (self, other) {
+            (Activation::LeakyRelu(a), Activation::LeakyRelu(b)) => a == b,
+            (Activation::Elu(a), Activation::Elu(b)) => a == b,
+            (Activation::Custom { .. }, Activation::Custom { .. }) => self.name() == other.name(),
+            (Activation::Custom { .. }, _) | (_, Activation::Custom { .. }) => false,
+            _ => self.name() == other.name(),
+        }
+    }
+}
+
+/// Custom activations are printed by name, as functions cannot be printed.
+impl fmt::Debug for Activation {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Activation::Relu => write!(f, "Relu"),
+            Activation::LeakyRelu(alpha) => write!(f, "LeakyRelu({:?})", alpha),
+            Activation::Elu(alpha) => write!(f, "Elu({:?})", alpha),
+            Activation::Selu => write!(f, "Selu"),
+            Activation::Gelu => write!(f, "Gelu"),
+            Activation::Tanh => write!(f, "Tanh"),
+            Activation::Softplus => write!(f, "Softplus"),
+            Activation::Mish => write!(f, "Mish"),
+            Activation::HardSigmoid => write!(f, "HardSigmoid"),
+            Activation::Linear => write!(f, "Linear"),
+            Activation::Sigmoid => write!(f, "Sigmoid"),
+            Activation::Swish => write!(f, "Swish"),
+            Activation::Softmax => write!(f, "Softmax"),
+            Activation::Custom { name, .. } => write!(f, "Custom {{ name: {:?}, .. }}", name),
+        }
+    }
+}
+
+impl fmt::Display for Activation {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Activation::LeakyRelu(alpha) | Activation::Elu(alpha) => write!(f, "{}({})", self.name(), alpha),
+            _ => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+/// Parses an activation function from the format written by `Display`. Custom activations cannot
+/// be parsed, as their functions are not known.
+impl str::FromStr for Activation {
+    type Err = NetworkError;
+
+    fn from_str(string : &str) -> Result<Activation, NetworkError> {
+        let (name, parameter) = split_parameter(string, "activation function")?;
+
+        match (name, parameter) {
+            ("relu", None) => Ok(Activation::Relu),
+            ("leaky_relu", Some(alpha)) => Ok(Activation::LeakyRelu(alpha)),
+            ("elu", Some(alpha)) => Ok(Activation::Elu(alpha)),
+            ("selu", None) => Ok(Activation::Selu),
+            ("gelu", None) => Ok(Activation::Gelu),
+            ("tanh", None) => Ok(Activation::Tanh),
+            ("softplus", None) => Ok(Activation::Softplus),
+            ("mish", None) => Ok(Activation::Mish),
+            ("hard_sigmoid", None) => Ok(Activation::HardSigmoid),
+            ("linear", None) => Ok(Activation::Linear),
+            ("sigmoid", None) => Ok(Activation::Sigmoid),
+            ("swish", None) => Ok(Activation::Swish),
+            ("softmax", None) => Ok(Activation::Softmax),
+            _ => Err(NetworkError::invalid_config(format!("Unknown activation function {}.", string)))
+        }
+    }
+}
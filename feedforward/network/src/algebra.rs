@@ -1,26 +1,36 @@
 use std::ops;
 use std::fmt;
 
+use crate::float::Float;
+use crate::kernels::Kernels;
+
 use std::vec::Vec as AlgVec;
 //use crate::unsafe_vec::UnsafeVec as AlgVec;
 
+/// The side length of the square blocks used when multiplying matrices, chosen so that a block
+/// of each matrix fits in cache together.
+#[cfg(not(feature = "blas"))]
+const BLOCK_SIZE : usize = 64;
+
 #[derive(Clone)]
-pub (crate) struct Vector(pub (crate) AlgVec<f64>);
+pub (crate) struct Vector<T = f64>(pub (crate) AlgVec<T>);
 
-impl fmt::Debug for Vector {
+impl<T : fmt::Debug> fmt::Debug for Vector<T> {
     fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Vector {{ Length: {}, Values: {:?} }}", self.0.len(), self.0)
     }
 }
 
+/// A matrix stored in row-major order. Layers are fed batches of inputs stacked as the rows of a
+/// matrix. The public operations panic if the shapes of the matrices they are given do not match.
 #[derive(Clone)]
-pub struct Matrix {
+pub struct Matrix<T = f64> {
     rows : usize,
     cols : usize,
-    values : AlgVec<f64>,
+    values : AlgVec<T>,
 }
 
-impl fmt::Debug for Matrix {
+impl<T : fmt::Debug> fmt::Debug for Matrix<T> {
     fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut matrix_strings = String::new();
     
@@ -40,10 +50,17 @@ impl fmt::Debug for Matrix {
     }
 }
 
-impl Vector {
+impl<T : Float> Vector<T> {
    
-    /// Creates a new vector from a Vec<f64>.
-    pub (crate) fn new(values : AlgVec<f64>) -> Vector {
+    /// Creates a new vector from a Vec<T>.
+    pub (crate) fn new(values : AlgVec<T>) -> Vector<T> {
+        Vector(values)
+    }
+
+    /// Creates a new vector of zeros.
+    pub (crate) fn zeros(len : usize) -> Vector<T> {
+        let mut values = AlgVec::with_capacity(len);
+        values.resize(len, T::ZERO);
         Vector(values)
     }
 
@@ -52,86 +69,56 @@ impl Vector {
         self.0.len()
     }
 
-    /// Indexes the vector.
-    fn index(&self, index : usize) -> f64 {
-        //self.0[index]
-        self.0[index]
-    }
-
     /// Creates an iterator from the vector.
-    pub (crate) fn iter(&self) -> impl Iterator<Item = &f64> {
+    pub (crate) fn iter(&self) -> impl Iterator<Item = &T> {
         self.0.iter()
     }
     
-    pub (crate) fn map<F>(&self, mut mapping : F) -> Vector
-        where F : FnMut(f64) -> f64 {
-        Vector::new(
-            self
-            .iter()
-            .map(|a| { mapping(*a) }) 
-            .collect()
-        )
-    }
-    
-    pub (crate) fn into_matrix(self) -> Matrix {
-        Matrix::new(self.len(), 1, self.0)
-    }
-
-    fn component_wise<F>(first : &Vector, second : &Vector, mut operation : F) -> Vector
-        where F : FnMut(f64, f64) -> f64 {
+    fn component_wise(first : &Vector<T>, second : &Vector<T>, kernel : fn(&[T], &[T], &mut [T])) -> Vector<T> {
         debug_assert!(first.len() == second.len());
 
-        Vector::new(
-            first
-            .0
-            .iter()
-            .zip(second.0.iter())
-            .map(|(a, b)| operation(*a, *b))
-            .collect()
-        )
+        let mut result = Vector::zeros(first.len());
+        kernel(&first.0, &second.0, &mut result.0);
+        result
     } 
+}    
 
-    pub (crate) fn outer_product(first : &Vector, second : &Vector) -> Matrix {
-        let mut values = AlgVec::with_capacity(first.len() * second.len());
-
-        for i in 0..first.len() {
-            for j in 0..second.len() {
-                values.push(
-                    first.index(i) * second.index(j)
-                );
-            }
-        }
+impl<T : Float> ops::Add<&Vector<T>> for &Vector<T> {
+    type Output = Vector<T>;
 
-        Matrix::new(first.len(), second.len(), values)
+    fn add(self, other : &Vector<T>) -> Vector<T> {
+        Vector::component_wise(self, other, Kernels::add)
     }
-}    
+}
 
-impl ops::Add<&Vector> for &Vector {
-    type Output = Vector;
+impl<T : Float> ops::Sub<&Vector<T>> for &Vector<T> {
+    type Output = Vector<T>;
 
-    fn add(self, other : &Vector) -> Vector {
-        Vector::component_wise(self, other, |a, b| a + b)
+    fn sub(self, other : &Vector<T>) -> Vector<T> {
+        Vector::component_wise(self, other, Kernels::sub)
     }
 }
 
-impl ops::Sub<&Vector> for &Vector {
-    type Output = Vector;
+impl<T : Float> ops::AddAssign<&Vector<T>> for Vector<T> {
+    fn add_assign(&mut self, other : &Vector<T>) {
+        debug_assert!(self.len() == other.len());
 
-    fn sub(self, other : &Vector) -> Vector {
-        Vector::component_wise(self, other, |a, b| a - b)
+        T::axpy(T::ONE, &other.0, &mut self.0);
     }
 }
 
-impl ops::Mul<&Vector> for f64 {
-    type Output = Vector;
+impl<T : Float> ops::Mul<T> for &Vector<T> {
+    type Output = Vector<T>;
 
-    fn mul(self, other : &Vector) -> Self::Output {
-        other.map(|a| self * a)
+    fn mul(self, scale : T) -> Self::Output {
+        let mut result = Vector::zeros(self.len());
+        T::scale(scale, &self.0, &mut result.0);
+        result
     }
 }
 
-impl Matrix {
-    pub (crate) fn new(rows : usize, cols : usize, values : AlgVec<f64>) -> Matrix {
+impl<T : Float> Matrix<T> {
+    pub (crate) fn new(rows : usize, cols : usize, values : AlgVec<T>) -> Matrix<T> {
         debug_assert!(values.len() == rows * cols);
 
         Matrix {
@@ -141,127 +128,299 @@ impl Matrix {
         }
     }
 
-    pub (crate) fn index(&self, row : usize, col : usize) -> f64 {
-        debug_assert!(row < self.rows && col < self.cols);
+    /// Creates a new matrix of zeros.
+    pub fn zeros(rows : usize, cols : usize) -> Matrix<T> {
+        let mut values = AlgVec::with_capacity(rows * cols);
+        values.resize(rows * cols, T::ZERO);
+        Matrix::new(rows, cols, values)
+    }
+
+    /// Creates a new matrix with the provided vectors, which must have equal lengths, as its rows.
+    pub (crate) fn from_rows(rows : &[Vector<T>]) -> Matrix<T> {
+        let cols = rows.first().map_or(0, |row| row.len());
+        let mut values = AlgVec::with_capacity(rows.len() * cols);
+
+        for row in rows {
+            debug_assert!(row.len() == cols);
+            values.extend(row.iter());
+        }
+
+        Matrix::new(rows.len(), cols, values)
+    }
+
+    /// Splits the matrix into a vector for each row.
+    pub (crate) fn to_rows(&self) -> Vec<Vector<T>> {
+        (0..self.rows)
+        .map(|row| Vector::new(self.row(row).to_vec()))
+        .collect()
+    }
+
+    /// Returns the values in the specified row.
+    pub fn row(&self, row : usize) -> &[T] {
+        assert!(row < self.rows, "Attempt to access row {} of a matrix with {} rows.", row, self.rows);
+
+        &self.values[(row * self.cols)..((row + 1) * self.cols)]
+    }
+
+    /// Returns the values of the matrix in row-major order.
+    pub fn values(&self) -> &[T] {
+        &self.values
+    }
+
+    /// Returns the values of the matrix in row-major order, for modification in place.
+    pub fn values_mut(&mut self) -> &mut [T] {
+        &mut self.values
+    }
+
+    /// Returns the number of rows in the matrix.
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Returns the number of columns in the matrix.
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    /// Multiplies two matrices. The product is computed in square blocks, so that the parts of
+    /// each matrix being worked on stay in cache, and within a block each row of the second
+    /// matrix is scaled and accumulated into a row of the result, so memory is walked contiguously.
+    #[cfg(not(feature = "blas"))]
+    pub fn multiply(first : &Matrix<T>, second : &Matrix<T>) -> Matrix<T> {
+        assert!(first.cols == second.rows, "Attempt to multiply a {}x{} matrix by a {}x{} matrix.", first.rows, first.cols, second.rows, second.cols);
+
+        let mut values = AlgVec::with_capacity(first.rows * second.cols);
+        values.resize(first.rows * second.cols, T::ZERO);
+
+        for row_block in (0..first.rows).step_by(BLOCK_SIZE) {
+            let row_end = usize::min(row_block + BLOCK_SIZE, first.rows);
+
+            for inner_block in (0..first.cols).step_by(BLOCK_SIZE) {
+                let inner_end = usize::min(inner_block + BLOCK_SIZE, first.cols);
+
+                for col_block in (0..second.cols).step_by(BLOCK_SIZE) {
+                    let col_end = usize::min(col_block + BLOCK_SIZE, second.cols);
+
+                    for i in row_block..row_end {
+                        let result_row = &mut values[(i * second.cols + col_block)..(i * second.cols + col_end)];
+
+                        for k in inner_block..inner_end {
+                            let scale = first.values[i * first.cols + k];
+                            let second_row = &second.values[(k * second.cols + col_block)..(k * second.cols + col_end)];
+
+                            T::axpy(scale, second_row, result_row);
+                        }
+                    }
+                }
+            }
+        }
 
-        self.values[row * self.cols + col]
+        Matrix::new(first.rows, second.cols, values)
     }
 
-    pub (crate) fn multiply(first : &Matrix, second : &Matrix) -> Matrix {
-        debug_assert!(first.cols == second.rows);
+    /// Multiplies the first matrix by the transpose of the second, without forming the transpose.
+    /// Each entry of the result is the dot product of a row of each matrix, so both are walked
+    /// contiguously, and this is done in blocks so that the rows being worked on stay in cache.
+    #[cfg(not(feature = "blas"))]
+    pub fn multiply_transpose(first : &Matrix<T>, second : &Matrix<T>) -> Matrix<T> {
+        assert!(first.cols == second.cols, "Attempt to multiply a {}x{} matrix by the transpose of a {}x{} matrix.", first.rows, first.cols, second.rows, second.cols);
+
+        let mut values = AlgVec::with_capacity(first.rows * second.rows);
+        values.resize(first.rows * second.rows, T::ZERO);
+
+        for row_block in (0..first.rows).step_by(BLOCK_SIZE) {
+            let row_end = usize::min(row_block + BLOCK_SIZE, first.rows);
+
+            for col_block in (0..second.rows).step_by(BLOCK_SIZE) {
+                let col_end = usize::min(col_block + BLOCK_SIZE, second.rows);
 
-        let mut unravelled = AlgVec::with_capacity(first.rows * second.cols);
+                for inner_block in (0..first.cols).step_by(BLOCK_SIZE) {
+                    let inner_end = usize::min(inner_block + BLOCK_SIZE, first.cols);
 
-        for i in 0..first.rows {
-            for j in 0..second.cols {
-                
-                let mut new_val = 0.0; 
+                    for i in row_block..row_end {
+                        let first_row = &first.values[(i * first.cols + inner_block)..(i * first.cols + inner_end)];
 
-                for k in 0..first.cols { // Equivalent to other.rows
-                    new_val += first.index(i, k) * second.index(k, j);
+                        for j in col_block..col_end {
+                            let second_row = &second.values[(j * second.cols + inner_block)..(j * second.cols + inner_end)];
+
+                            values[i * second.rows + j] += T::dot(first_row, second_row);
+                        }
+                    }
                 }
+            }
+        }
+
+        Matrix::new(first.rows, second.rows, values)
+    }
 
-                unravelled.push(new_val);
+    /// Multiplies the transpose of the first matrix by the second, without forming the transpose.
+    /// Each row of the second matrix is scaled by each entry in the corresponding row of the first
+    /// and accumulated into a row of the result, which is done in blocks of rows of the result so
+    /// that they stay in cache.
+    #[cfg(not(feature = "blas"))]
+    pub fn transpose_multiply(first : &Matrix<T>, second : &Matrix<T>) -> Matrix<T> {
+        assert!(first.rows == second.rows, "Attempt to multiply the transpose of a {}x{} matrix by a {}x{} matrix.", first.rows, first.cols, second.rows, second.cols);
+
+        let mut values = AlgVec::with_capacity(first.cols * second.cols);
+        values.resize(first.cols * second.cols, T::ZERO);
+
+        for row_block in (0..first.cols).step_by(BLOCK_SIZE) {
+            let row_end = usize::min(row_block + BLOCK_SIZE, first.cols);
+
+            for k in 0..first.rows {
+                let second_row = second.row(k);
+
+                for i in row_block..row_end {
+                    let result_row = &mut values[(i * second.cols)..((i + 1) * second.cols)];
+
+                    T::axpy(first.values[k * first.cols + i], second_row, result_row);
+                }
             }
         }
 
-        Matrix::new(first.rows, second.cols, unravelled)
+        Matrix::new(first.cols, second.cols, values)
     }
 
-    pub (crate) fn transpose(&self) -> Matrix {
+    /// Adds the vector to every row of the matrix.
+    pub (crate) fn add_to_rows(&mut self, vector : &Vector<T>) {
+        debug_assert!(self.cols == vector.len());
+
+        for row in self.values.chunks_mut(self.cols) {
+            T::axpy(T::ONE, &vector.0, row);
+        }
+    }
+
+    /// Creates a new matrix by applying the mapping to each row, which must preserve its length.
+    pub fn map_rows<F>(&self, mut mapping : F) -> Matrix<T>
+        where F : FnMut(&[T]) -> Vec<T> {
         let mut values = AlgVec::with_capacity(self.rows * self.cols);
 
         for row in 0..self.rows {
-            for col in 0..self.cols {
-                values.push(self.index(row, col));
-            }
+            let mapped = mapping(self.row(row));
+            assert!(mapped.len() == self.cols, "Attempt to map a row of {} values to {} values.", self.cols, mapped.len());
+            values.extend(mapped);
         }
 
-        Matrix::new(self.cols, self.rows, values)
+        Matrix::new(self.rows, self.cols, values)
     }
 
-    pub (crate) fn diagonal(vector : &Vector) -> Matrix {
-        let mut values = AlgVec::with_capacity(vector.len() * vector.len());
+    /// Creates a new matrix by applying the mapping to each pair of corresponding rows of two
+    /// matrices of the same size, which must preserve their length.
+    pub fn zip_map_rows<F>(first : &Matrix<T>, second : &Matrix<T>, mut mapping : F) -> Matrix<T>
+        where F : FnMut(&[T], &[T]) -> Vec<T> {
+        Matrix::check_same_size(first, second);
 
-        for row in 0..vector.len() {
-            for col in 0..vector.len() {
-                values.push(
-                    if row == col { vector.index(row) } else { 0.0 }
-                );
-            }
+        let mut values = AlgVec::with_capacity(first.rows * first.cols);
+
+        for row in 0..first.rows {
+            let mapped = mapping(first.row(row), second.row(row));
+            assert!(mapped.len() == first.cols, "Attempt to map a row of {} values to {} values.", first.cols, mapped.len());
+            values.extend(mapped);
         }
 
-        Matrix::new(vector.len(), vector.len(), values)
+        Matrix::new(first.rows, first.cols, values)
     }
 
-    fn component_wise<F>(first : &Matrix, second : &Matrix, mut operation : F) -> Matrix
-        where F : FnMut(f64, f64) -> f64 {
-        debug_assert!(first.rows == second.rows && first.cols == second.cols);
+    /// Sums each column of the matrix, which is the sum of its rows.
+    pub (crate) fn column_sums(&self) -> Vector<T> {
+        let mut result = Vector::zeros(self.cols);
 
-        Matrix::new(
-            first.rows,
-            first.cols,
-            first 
-            .values
-            .iter()
-            .zip(second.values.iter())
-            .map(|(a, b)| operation(*a, *b))
-            .collect()
-        )
-    } 
+        for row in self.values.chunks(self.cols) {
+            T::axpy(T::ONE, row, &mut result.0);
+        }
 
-    pub (crate) fn into_vector(self) -> Vector {
-        debug_assert!(self.rows == 1 || self.cols == 1);
+        result
+    }
 
-        Vector::new(self.values)
+    /// Multiplies two matrices of the same size component-wise.
+    pub fn hadamard(first : &Matrix<T>, second : &Matrix<T>) -> Matrix<T> {
+        Matrix::component_wise(first, second, Kernels::mul)
+    }
+
+    fn component_wise(first : &Matrix<T>, second : &Matrix<T>, kernel : fn(&[T], &[T], &mut [T])) -> Matrix<T> {
+        Matrix::check_same_size(first, second);
+
+        let mut result = Matrix::zeros(first.rows, first.cols);
+        kernel(&first.values, &second.values, &mut result.values);
+        result
+    } 
+
+    /// Panics unless two matrices used component-wise are the same size.
+    fn check_same_size(first : &Matrix<T>, second : &Matrix<T>) {
+        assert!(first.rows == second.rows && first.cols == second.cols, "Attempt to combine a {}x{} matrix with a {}x{} matrix component-wise.", first.rows, first.cols, second.rows, second.cols);
     }
 }
 
-impl ops::Add<&Matrix> for &Matrix {
-    type Output = Matrix;
+/// With the `blas` feature, the matrix products are computed by the system BLAS library instead.
+#[cfg(feature = "blas")]
+impl<T : Float> Matrix<T> {
+    /// Multiplies two matrices.
+    pub fn multiply(first : &Matrix<T>, second : &Matrix<T>) -> Matrix<T> {
+        assert!(first.cols == second.rows, "Attempt to multiply a {}x{} matrix by a {}x{} matrix.", first.rows, first.cols, second.rows, second.cols);
+
+        let mut result = Matrix::zeros(first.rows, second.cols);
+        T::gemm(false, false, first.rows, second.cols, first.cols, &first.values, &second.values, &mut result.values);
+        result
+    }
+
+    /// Multiplies the first matrix by the transpose of the second, without forming the transpose.
+    pub fn multiply_transpose(first : &Matrix<T>, second : &Matrix<T>) -> Matrix<T> {
+        assert!(first.cols == second.cols, "Attempt to multiply a {}x{} matrix by the transpose of a {}x{} matrix.", first.rows, first.cols, second.rows, second.cols);
+
+        let mut result = Matrix::zeros(first.rows, second.rows);
+        T::gemm(false, true, first.rows, second.rows, first.cols, &first.values, &second.values, &mut result.values);
+        result
+    }
+
+    /// Multiplies the transpose of the first matrix by the second, without forming the transpose.
+    pub fn transpose_multiply(first : &Matrix<T>, second : &Matrix<T>) -> Matrix<T> {
+        assert!(first.rows == second.rows, "Attempt to multiply the transpose of a {}x{} matrix by a {}x{} matrix.", first.rows, first.cols, second.rows, second.cols);
 
-    fn add(self, other : &Matrix) -> Self::Output {
-        Matrix::component_wise(self, other, |a, b| a + b)
+        let mut result = Matrix::zeros(first.cols, second.cols);
+        T::gemm(true, false, first.cols, second.cols, first.rows, &first.values, &second.values, &mut result.values);
+        result
     }
 }
 
-impl ops::Sub<&Matrix> for &Matrix {
-    type Output = Matrix;
+impl<T : Float> ops::Add<&Matrix<T>> for &Matrix<T> {
+    type Output = Matrix<T>;
 
-    fn sub(self, other : &Matrix) -> Self::Output {
-        Matrix::component_wise(self, other, |a, b| a - b)
+    fn add(self, other : &Matrix<T>) -> Self::Output {
+        Matrix::component_wise(self, other, Kernels::add)
     }
 }
 
-impl ops::Mul<&Matrix> for f64 {
-    type Output = Matrix;
+impl<T : Float> ops::Sub<&Matrix<T>> for &Matrix<T> {
+    type Output = Matrix<T>;
 
-    fn mul(self, other : &Matrix) -> Self::Output {
-        Matrix::new(
-            other.rows,
-            other.cols,
-            other 
-            .values
-            .iter()
-            .map(|a| self * a)
-            .collect()
-        )
+    fn sub(self, other : &Matrix<T>) -> Self::Output {
+        Matrix::component_wise(self, other, Kernels::sub)
     }
 }
 
-impl ops::Mul<&Matrix> for &Matrix {
-    type Output = Matrix;
+impl<T : Float> ops::AddAssign<&Matrix<T>> for Matrix<T> {
+    fn add_assign(&mut self, other : &Matrix<T>) {
+        Matrix::check_same_size(self, other);
 
-    fn mul(self, other : &Matrix) -> Self::Output {
-        Matrix::multiply(self, other)
+        T::axpy(T::ONE, &other.values, &mut self.values);
     }
 }
 
+impl<T : Float> ops::Mul<T> for &Matrix<T> {
+    type Output = Matrix<T>;
 
-impl ops::Mul<&Vector> for &Matrix {
-    type Output = Vector;
+    fn mul(self, scale : T) -> Self::Output {
+        let mut result = Matrix::zeros(self.rows, self.cols);
+        T::scale(scale, &self.values, &mut result.values);
+        result
+    }
+}
+
+impl<T : Float> ops::Mul<&Matrix<T>> for &Matrix<T> {
+    type Output = Matrix<T>;
 
-    fn mul(self, other : &Vector) -> Self::Output {
-        (self * &other.clone().into_matrix()).into_vector()
+    fn mul(self, other : &Matrix<T>) -> Self::Output {
+        Matrix::multiply(self, other)
     }
 }
+
@@ -0,0 +1,76 @@
+//! Bindings to the matrix products from a system CBLAS library, used by `algebra` when the `blas`
+//! feature is enabled. Matrices are stored contiguously in row-major order, so can be passed to
+//! CBLAS directly with the leading dimension being the number of columns.
+
+use std::os::raw::c_int;
+
+const ROW_MAJOR : c_int = 101;
+const NO_TRANS : c_int = 111;
+const TRANS : c_int = 112;
+
+extern "C" {
+    fn cblas_dgemm(
+        layout : c_int, trans_a : c_int, trans_b : c_int,
+        m : c_int, n : c_int, k : c_int,
+        alpha : f64, a : *const f64, lda : c_int, b : *const f64, ldb : c_int,
+        beta : f64, c : *mut f64, ldc : c_int
+    );
+
+    fn cblas_sgemm(
+        layout : c_int, trans_a : c_int, trans_b : c_int,
+        m : c_int, n : c_int, k : c_int,
+        alpha : f32, a : *const f32, lda : c_int, b : *const f32, ldb : c_int,
+        beta : f32, c : *mut f32, ldc : c_int
+    );
+}
+
+/// Converts a dimension to the integer type used by CBLAS, which is narrower than `usize`.
+fn dimension(value : usize) -> c_int {
+    match c_int::try_from(value) {
+        Ok(value) => value,
+        Err(_) => panic!("Attempt to pass a matrix dimension of {} to BLAS, which is too large.", value)
+    }
+}
+
+/// Generates a safe wrapper around the matrix product of one precision.
+macro_rules! wrappers {
+    ($float : ident, $gemm : ident, $cblas_gemm : ident) => {
+        /// Writes the product of a `rows`x`inner` matrix (or the transpose of an `inner`x`rows`
+        /// matrix if `transpose_first`) and an `inner`x`cols` matrix (or the transpose of a
+        /// `cols`x`inner` matrix if `transpose_second`) to the `rows`x`cols` output.
+        #[allow(clippy::too_many_arguments)]
+        pub (crate) fn $gemm(
+            transpose_first : bool, transpose_second : bool,
+            rows : usize, cols : usize, inner : usize,
+            first : &[$float], second : &[$float], output : &mut [$float]) {
+
+            assert!(first.len() == rows * inner && second.len() == inner * cols && output.len() == rows * cols);
+
+            // Empty matrices have a leading dimension of zero, which CBLAS rejects.
+            if rows == 0 || cols == 0 {
+                return
+            }
+            if inner == 0 {
+                output.fill(0.0);
+                return
+            }
+
+            let first_cols = if transpose_first { rows } else { inner };
+            let second_cols = if transpose_second { inner } else { cols };
+
+            unsafe {
+                $cblas_gemm(
+                    ROW_MAJOR,
+                    if transpose_first { TRANS } else { NO_TRANS },
+                    if transpose_second { TRANS } else { NO_TRANS },
+                    dimension(rows), dimension(cols), dimension(inner),
+                    1.0, first.as_ptr(), dimension(first_cols), second.as_ptr(), dimension(second_cols),
+                    0.0, output.as_mut_ptr(), dimension(cols)
+                );
+            }
+        }
+    };
+}
+
+wrappers!(f64, dgemm, cblas_dgemm);
+wrappers!(f32, sgemm, cblas_sgemm);
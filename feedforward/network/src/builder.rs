@@ -0,0 +1,183 @@
+use std::marker;
+
+use crate::Network;
+use crate::activation::Activation;
+use crate::error::NetworkError;
+use crate::float::Float;
+use crate::layer::{Layer, Dense, ActivationLayer, Dropout, BatchNorm, LayerNorm};
+use crate::regularisation::Regularisation;
+use crate::weights_gen;
+use crate::weights_gen::{ChaCha8Rng, Initialiser};
+
+/// A layer added to the builder, which for fully connected layers is created once the number of
+/// inputs to it is known.
+#[derive(Debug, Clone)]
+enum Pending<T : Float> {
+    Dense {
+        size : usize,
+        activ : Activation,
+        weights_init : Option<Initialiser>,
+        biases_init : Initialiser,
+    },
+    Dropout(f64),
+    BatchNorm,
+    LayerNorm,
+    Layer(Box<dyn Layer<T>>),
+}
+
+/// Builds a feed forward neural network one layer at a time, starting with the number of inputs
+/// and then adding each layer in turn, such as
+/// `NetworkBuilder::new().input(784).dense(128, Activation::Relu).dense(10, Activation::Softmax)`.
+/// The configuration is only checked when the network is built, which returns an error describing
+/// the first problem found.
+#[derive(Debug, Clone)]
+pub struct NetworkBuilder<T : Float = f64> {
+    input : Option<usize>,
+    layers : Vec<Pending<T>>,
+    weights_init : Option<Initialiser>,
+    biases_init : Initialiser,
+    regularisation : Regularisation,
+    threads : usize,
+    precision : marker::PhantomData<T>,
+}
+
+impl<T : Float> Default for NetworkBuilder<T> {
+    fn default() -> NetworkBuilder<T> {
+        NetworkBuilder::new()
+    }
+}
+
+impl<T : Float> NetworkBuilder<T> {
+    /// Creates a builder for a network with no layers.
+    pub fn new() -> NetworkBuilder<T> {
+        NetworkBuilder {
+            input : None,
+            layers : Vec::new(),
+            weights_init : None,
+            biases_init : Initialiser::Zeros,
+            regularisation : Regularisation::new(),
+            threads : 1,
+            precision : marker::PhantomData,
+        }
+    }
+
+    /// Sets the number of inputs to the network.
+    pub fn input(mut self, size : usize) -> NetworkBuilder<T> {
+        self.input = Some(size);
+        self
+    }
+
+    /// Adds a fully connected layer with the specified number of neurons, followed by an
+    /// activation layer applying the activation function, after the layers added so far. Only the
+    /// output layer can use softmax.
+    pub fn dense(mut self, size : usize, activ : Activation) -> NetworkBuilder<T> {
+        self.layers.push(Pending::Dense {
+            size,
+            activ,
+            weights_init : self.weights_init,
+            biases_init : self.biases_init,
+        });
+        self
+    }
+
+    /// Adds a dropout layer after the layers added so far, which drops each of its inputs with
+    /// probability `rate` while training.
+    pub fn dropout(mut self, rate : f64) -> NetworkBuilder<T> {
+        self.layers.push(Pending::Dropout(rate));
+        self
+    }
+
+    /// Adds a batch normalisation layer after the layers added so far, with the default momentum
+    /// and epsilon. Layers with other settings can be added with `layer`.
+    pub fn batch_norm(mut self) -> NetworkBuilder<T> {
+        self.layers.push(Pending::BatchNorm);
+        self
+    }
+
+    /// Adds a layer normalisation layer after the layers added so far, with the default epsilon.
+    pub fn layer_norm(mut self) -> NetworkBuilder<T> {
+        self.layers.push(Pending::LayerNorm);
+        self
+    }
+
+    /// Adds a layer after the layers added so far, such as one defined outside this crate. It
+    /// must take as many inputs as the layer before it outputs.
+    pub fn layer(mut self, layer : Box<dyn Layer<T>>) -> NetworkBuilder<T> {
+        self.layers.push(Pending::Layer(layer));
+        self
+    }
+
+    /// Sets the initialisers of the weights and biases of the layers added after this. Until this
+    /// is called, the biases are initialised to zero and the weights with the scheme that suits the
+    /// activation function of their layer: He normal for the relu family, LeCun normal for selu and
+    /// Xavier normal otherwise.
+    pub fn initialisers(mut self, weights_init : Initialiser, biases_init : Initialiser) -> NetworkBuilder<T> {
+        self.weights_init = Some(weights_init);
+        self.biases_init = biases_init;
+        self
+    }
+
+    /// Sets the L1 and L2 penalties and max-norm constraint applied while training the network.
+    pub fn regularisation(mut self, regularisation : Regularisation) -> NetworkBuilder<T> {
+        self.regularisation = regularisation;
+        self
+    }
+
+    /// Sets the number of threads used to train and test the network.
+    pub fn threads(mut self, threads : usize) -> NetworkBuilder<T> {
+        self.threads = threads;
+        self
+    }
+
+    /// Builds the network, initialising it with a random number generator created from the seed.
+    pub fn build(self, seed : u64) -> Result<Network<T>, NetworkError> {
+        self.build_with_rng(weights_gen::seeded(seed))
+    }
+
+    /// Builds the network, which takes ownership of the random number generator.
+    pub fn build_with_rng(self, mut rng : ChaCha8Rng) -> Result<Network<T>, NetworkError> {
+        let mut size = match self.input {
+            Some(input) => input,
+            None => return Err(NetworkError::invalid_config("Attempt to build a neural network without setting the number of inputs."))
+        };
+        if self.layers.is_empty() {
+            return Err(NetworkError::invalid_config("Attempt to build a neural network without any layers after the input."))
+        }
+
+        let mut layers : Vec<Box<dyn Layer<T>>> = Vec::with_capacity(2 * self.layers.len());
+        for pending in self.layers {
+            match pending {
+                Pending::Dense { size : outputs, activ, weights_init, biases_init } => {
+                    let weights_init = weights_init.unwrap_or_else(|| default_weights_initialiser(&activ));
+                    layers.push(Box::new(Dense::new(size, outputs, weights_init, biases_init, &mut rng)?));
+                    layers.push(Box::new(ActivationLayer::new(outputs, activ)));
+                },
+                Pending::Dropout(rate) => layers.push(Box::new(Dropout::new(size, rate)?)),
+                Pending::BatchNorm => layers.push(Box::new(BatchNorm::new(size))),
+                Pending::LayerNorm => layers.push(Box::new(LayerNorm::new(size))),
+                Pending::Layer(layer) => layers.push(layer),
+            }
+            size = layers[layers.len() - 1].outputs();
+        }
+
+        Network::from_layers(layers, rng)?
+        .with_regularisation(self.regularisation)?
+        .with_threads(self.threads)
+    }
+}
+
+impl<T : Float> Network<T> {
+    /// Creates a builder for a network, equivalent to `NetworkBuilder::new`.
+    pub fn builder() -> NetworkBuilder<T> {
+        NetworkBuilder::new()
+    }
+}
+
+/// Returns the weights initialiser that suits the activation function.
+fn default_weights_initialiser(activ : &Activation) -> Initialiser {
+    match activ {
+        Activation::Relu | Activation::LeakyRelu(_) | Activation::Elu(_) | Activation::Gelu | Activation::Swish | Activation::Mish => Initialiser::HeNormal,
+        Activation::Selu => Initialiser::LecunNormal,
+        _ => Initialiser::XavierNormal,
+    }
+}
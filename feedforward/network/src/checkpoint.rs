@@ -0,0 +1,286 @@
+use std::fs;
+use std::path;
+
+use crate::Network;
+use crate::float::Float;
+use crate::optimizer::Optimizer;
+use crate::regularisation::Regularisation;
+use crate::schedule::Scheduler;
+use crate::error::NetworkError;
+use crate::save::{Lines, join, expect_line, parse, parse_values};
+use crate::weights_gen;
+
+/// Identifies a file as a training checkpoint.
+const HEADER : &str = "feedforward-checkpoint";
+
+/// The version of the format written by `Checkpoint::save`.
+const VERSION : u32 = 5;
+
+/// A snapshot of a training run, capturing everything needed to continue training exactly as if
+/// it had not been interrupted: the network along with the position of its random number
+/// generator, its regularisation and its number of threads, the state of the optimizer and how far
+/// training has progressed. Training is only deterministic for a given number of threads, so the
+/// network is restored with the number it was trained with. If training is scheduled, the state of
+/// the schedule is captured too with `with_scheduler`.
+#[derive(Debug, Clone)]
+pub struct Checkpoint<T : Float = f64> {
+    network : Network<T>,
+    optimizer : String,
+    optimizer_state : Vec<Vec<f64>>,
+    schedule_state : Option<Vec<f64>>,
+    epoch : usize,
+    step : usize,
+}
+
+impl<T : Float> Checkpoint<T> {
+    /// Captures a checkpoint after `epoch` epochs and `step` updates of training.
+    pub fn new(network : &Network<T>, optimizer : &dyn Optimizer<T>, epoch : usize, step : usize) -> Checkpoint<T> {
+        Checkpoint {
+            network : network.clone(),
+            optimizer : String::from(optimizer.name()),
+            optimizer_state : optimizer.state(),
+            schedule_state : None,
+            epoch,
+            step
+        }
+    }
+
+    /// Captures the state of the scheduler setting the optimizer's learning rate, such as the
+    /// history of a `ReduceOnPlateau` schedule.
+    pub fn with_scheduler(mut self, scheduler : &Scheduler) -> Checkpoint<T> {
+        self.schedule_state = Some(scheduler.state());
+        self
+    }
+
+    /// Returns the number of epochs of training completed when the checkpoint was captured.
+    pub fn epoch(&self) -> usize {
+        self.epoch
+    }
+
+    /// Returns the number of updates made to the network when the checkpoint was captured.
+    pub fn step(&self) -> usize {
+        self.step
+    }
+
+    /// Restores the optimizer to its state when the checkpoint was captured, returning the network
+    /// along with the epoch and step counters. The optimizer must be of the same type as the one
+    /// the checkpoint was captured with, and should have the same configuration.
+    pub fn resume(self, optimizer : &mut dyn Optimizer<T>) -> Result<(Network<T>, usize, usize), NetworkError> {
+        if optimizer.name() != self.optimizer {
+            return Err(NetworkError::invalid_config(format!("Attempt to resume from a checkpoint of an optimizer of type {} with one of type {}.", self.optimizer, optimizer.name())))
+        }
+
+        let sizes : Vec<usize> =
+            self.network.layers
+            .iter()
+            .flat_map(|layer| layer.parameters())
+            .map(|parameters| parameters.len())
+            .collect();
+        optimizer.load_state(self.optimizer_state, &sizes)?;
+
+        Ok((self.network, self.epoch, self.step))
+    }
+
+    /// Resumes as `resume` does, and also restores the scheduler to its state and position when
+    /// the checkpoint was captured. The scheduler must use the same type of schedule as the one
+    /// the checkpoint was captured with. Both are checked before either is changed, so neither is
+    /// left partly restored if the other does not match.
+    pub fn resume_scheduled(mut self, optimizer : &mut dyn Optimizer<T>, scheduler : &mut Scheduler) -> Result<(Network<T>, usize, usize), NetworkError> {
+        let state = match self.schedule_state.take() {
+            Some(state) => state,
+            None => return Err(NetworkError::invalid_config("Attempt to resume a scheduler from a checkpoint captured without one."))
+        };
+
+        scheduler.validate_state(&state)?;
+
+        let (network, epoch, step) = self.resume(optimizer)?;
+        scheduler.load_state(state)?;
+        scheduler.set_position(epoch, step);
+
+        Ok((network, epoch, step))
+    }
+
+    /// Saves the checkpoint to a text file.
+    pub fn save(&self, path : path::PathBuf) -> Result<(), NetworkError> {
+        let (seed, stream, word_pos) = weights_gen::state(&self.network.rng);
+        let seed : Vec<String> = seed.iter().map(|byte| byte.to_string()).collect();
+
+        let mut contents = format!("{} {}\n", HEADER, VERSION);
+        contents.push_str(&format!("epoch {}\nstep {}\n", self.epoch, self.step));
+        contents.push_str(&format!("rng {} {} {}\n", seed.join(" "), stream, word_pos));
+        contents.push_str(&format!("threads {}\n", self.network.threads));
+        contents.push_str(&self.network.regularisation.serialise());
+        contents.push_str(&format!("optimizer {} {}\n", self.optimizer, self.optimizer_state.len()));
+        for state in &self.optimizer_state {
+            contents.push_str(&format!("state {}\n", join(state)));
+        }
+        match &self.schedule_state {
+            Some(state) => contents.push_str(&format!("scheduled true\nschedule {}\n", join(state))),
+            None => contents.push_str("scheduled false\n"),
+        }
+        contents.push_str(&self.network.serialise()?);
+
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    /// Loads a checkpoint previously written by `Checkpoint::save`.
+    pub fn load(path : path::PathBuf) -> Result<Checkpoint<T>, NetworkError> {
+        let contents = fs::read_to_string(path)?;
+        let mut lines = Lines::new(&contents);
+
+        let mut header = expect_line(&mut lines, HEADER)?;
+        let version : u32 = parse(&mut header, "version")?;
+        if version != VERSION {
+            return Err(header.error(format!("Checkpoint has format version {}, but only version {} is supported.", version, VERSION)))
+        }
+
+        let epoch : usize = parse(&mut expect_line(&mut lines, "epoch")?, "epoch")?;
+        let step : usize = parse(&mut expect_line(&mut lines, "step")?, "step")?;
+
+        let mut rng = expect_line(&mut lines, "rng")?;
+        let mut seed = [0; 32];
+        for byte in seed.iter_mut() {
+            *byte = parse(&mut rng, "random number generator seed")?;
+        }
+        let stream : u64 = parse(&mut rng, "random number generator stream")?;
+        let word_pos : u128 = parse(&mut rng, "random number generator position")?;
+
+        let mut threads_line = expect_line(&mut lines, "threads")?;
+        let threads : usize = parse(&mut threads_line, "number of threads")?;
+        if threads == 0 {
+            return Err(threads_line.error("Checkpoint has a network with zero threads."))
+        }
+        let regularisation = Regularisation::deserialise(&mut lines)?;
+
+        let mut optimizer_line = expect_line(&mut lines, "optimizer")?;
+        let optimizer : String = parse(&mut optimizer_line, "optimizer name")?;
+        let num_states : usize = parse(&mut optimizer_line, "optimizer state size")?;
+        let mut optimizer_state = Vec::new();
+        for _ in 0..num_states {
+            optimizer_state.push(parse_values(expect_line(&mut lines, "state")?, "optimizer state")?);
+        }
+
+        let scheduled : bool = parse(&mut expect_line(&mut lines, "scheduled")?, "whether training is scheduled")?;
+        let schedule_state =
+            if scheduled {
+                Some(parse_values(expect_line(&mut lines, "schedule")?, "schedule state")?)
+            }
+            else {
+                None
+            };
+
+        let network =
+            Network::deserialise(&mut lines)?
+            .with_rng(weights_gen::restore(seed, stream, word_pos))
+            .with_threads(threads)?
+            .with_regularisation(regularisation)?;
+
+        Ok(Checkpoint {
+            network,
+            optimizer,
+            optimizer_state,
+            schedule_state,
+            epoch,
+            step
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, ops, process};
+
+    use super::Checkpoint;
+    use crate::{DataSet, Network};
+    use crate::activation::Activation;
+    use crate::algebra::Vector;
+    use crate::loss::MeanSquaredError;
+    use crate::optimizer::{Optimizer, Adam, Sgd};
+    use crate::schedule::{Scheduler, ReduceOnPlateau, StepDecay, Interval};
+    use crate::weights_gen;
+
+    const EPOCHS : usize = 4;
+    const BATCH_SIZE : usize = 5;
+
+    fn data_set(quantity : usize, entries : usize, offset : f64) -> DataSet {
+        DataSet(
+            (0..quantity)
+            .map(|set| Vector::new((0..entries).map(|entry| ((set * entries + entry) as f64 + offset).sin()).collect()))
+            .collect()
+        )
+    }
+
+    /// Creates a network whose training draws from its generator for both shuffling and dropout,
+    /// and which sums statistics across its threads.
+    fn network() -> Network {
+        Network::builder()
+        .input(3)
+        .dense(6, Activation::Tanh)
+        .batch_norm()
+        .dropout(0.3)
+        .dense(2, Activation::Sigmoid)
+        .threads(2)
+        .build(11)
+        .unwrap()
+    }
+
+    fn train(network : &mut Network, optimizer : &mut Adam, epochs : ops::Range<usize>, step : &mut usize) {
+        let (input, expected) = (data_set(23, 3, 0.0), data_set(23, 2, 0.5));
+        for _ in epochs {
+            *step += network.train_batch(optimizer, &MeanSquaredError, BATCH_SIZE, &input, &expected).unwrap();
+        }
+    }
+
+    #[test]
+    fn resumed_training_matches_uninterrupted() {
+        let (mut uninterrupted, mut optimizer, mut step) = (network(), Adam::new(0.01).unwrap(), 0);
+        train(&mut uninterrupted, &mut optimizer, 0..EPOCHS, &mut step);
+
+        let (mut interrupted, mut optimizer, mut step) = (network(), Adam::new(0.01).unwrap(), 0);
+        train(&mut interrupted, &mut optimizer, 0..EPOCHS / 2, &mut step);
+        let path = env::temp_dir().join(format!("network-checkpoint-test-{}.txt", process::id()));
+        Checkpoint::new(&interrupted, &optimizer, EPOCHS / 2, step).save(path.clone()).unwrap();
+        drop((interrupted, optimizer));
+
+        let mut optimizer = Adam::new(0.01).unwrap();
+        let (mut resumed, epoch, mut step) = Checkpoint::load(path.clone()).unwrap().resume(&mut optimizer).unwrap();
+        fs::remove_file(path).unwrap();
+        train(&mut resumed, &mut optimizer, epoch..EPOCHS, &mut step);
+
+        assert_eq!(resumed.threads(), 2);
+        assert_eq!(uninterrupted.serialise().unwrap(), resumed.serialise().unwrap());
+        assert_eq!(weights_gen::state(&uninterrupted.rng), weights_gen::state(&resumed.rng));
+    }
+
+    #[test]
+    fn mismatched_optimizer_leaves_scheduler_unchanged() {
+        let mut captured = Scheduler::new(Box::new(ReduceOnPlateau::new(0.5, 0, 1e-6).unwrap()), 0.01, Interval::Epoch);
+        captured.observe(2.0);
+        captured.observe(3.0);
+        let checkpoint = Checkpoint::new(&network(), &Adam::new(0.01).unwrap(), 3, 12).with_scheduler(&captured);
+
+        let mut scheduler = Scheduler::new(Box::new(ReduceOnPlateau::new(0.5, 0, 1e-6).unwrap()), 0.01, Interval::Epoch);
+        let state = scheduler.state();
+        assert!(checkpoint.resume_scheduled(&mut Sgd::new(0.01).unwrap(), &mut scheduler).is_err());
+
+        assert_eq!(scheduler.state(), state);
+        assert_eq!(scheduler.learning_rate(), 0.01);
+    }
+
+    #[test]
+    fn mismatched_scheduler_leaves_optimizer_unchanged() {
+        let mut captured = Scheduler::new(Box::new(ReduceOnPlateau::new(0.5, 0, 1e-6).unwrap()), 0.01, Interval::Epoch);
+        captured.observe(2.0);
+        let (mut network, mut optimizer, mut step) = (network(), Adam::new(0.01).unwrap(), 0);
+        train(&mut network, &mut optimizer, 0..1, &mut step);
+        let checkpoint = Checkpoint::new(&network, &optimizer, 1, step).with_scheduler(&captured);
+
+        let mut optimizer = Adam::new(0.01).unwrap();
+        let state = Optimizer::<f64>::state(&optimizer);
+        let mut scheduler = Scheduler::new(Box::new(StepDecay::new(2, 0.5).unwrap()), 0.01, Interval::Epoch);
+        assert!(checkpoint.resume_scheduled(&mut optimizer, &mut scheduler).is_err());
+
+        assert_eq!(Optimizer::<f64>::state(&optimizer), state);
+    }
+}
@@ -1,36 +1,40 @@
 use std::path;
+use std::ops;
 
-use crate::algebra::Vector;
+use crate::algebra::{Vector, Matrix};
+use crate::error::NetworkError;
+use crate::float::Float;
 use crate::DataSet;
 
 use std::vec::Vec as AlgVec;
 //use crate::unsafe_vec::UnsafeVec as AlgVec;
 
-impl DataSet {
+impl<T : Float> DataSet<T> {
     /// Reads a data set from a CSV file, where each row is a set of inputs and each column
-    /// corresponds with an input neuron.
-    pub fn from_csv(path : path::PathBuf, has_headers : bool) -> Result<DataSet, String> {
-        let mut data : Vec<Vector> = Vec::new();
-        let mut reader = match csv::ReaderBuilder::new()
+    /// corresponds with an input neuron. A cell which is not a number is reported with its row and
+    /// column, as is a row with a different number of entries to the first.
+    pub fn from_csv(path : path::PathBuf, has_headers : bool) -> Result<DataSet<T>, NetworkError> {
+        let mut data : Vec<Vector<T>> = Vec::new();
+        let mut reader = csv::ReaderBuilder::new()
             .has_headers(has_headers)
-            .from_path(path) {
-                Ok(reader) => reader,
-                Err(error) => return Err(error.to_string())
-            };
+            .flexible(true)
+            .from_path(path)?;
 
         let mut length = 0;
 
         for result in reader.records() {
-            let mut row = AlgVec::new();
-            let row_record = match result {
-                Ok(row_record) => row_record,
-                Err(error) => return Err(error.to_string())
-            };
-            for value in row_record.iter() {
-                row.push(value.parse::<f64>().unwrap())
+            let row_record = result?;
+            let row_no = row_record.position().map_or(data.len() + 1, |position| position.line() as usize);
+
+            let mut row = AlgVec::with_capacity(row_record.len());
+            for (column, value) in row_record.iter().enumerate() {
+                match value.trim().parse::<T>() {
+                    Ok(value) => row.push(value),
+                    Err(error) => return Err(NetworkError::parse(row_no, column + 1, format!("Invalid value {:?} in data set: {}.", value, error)))
+                }
             }
             if length == 0 { length = row.len() }
-            else if length != row.len() { return Err(String::from("Input data set does not have consistent length rows.")) }
+            else if length != row.len() { return Err(NetworkError::shape_mismatch(format!("the number of entries in row {} of the data set", row_no), length, row.len())) }
             data.push(Vector::new(row));
         }
 
@@ -51,27 +55,36 @@ impl DataSet {
     }
 
     /// Returns a reference to the data set at the specified index.
-    pub fn get(&self, index : usize) -> &Vec<f64> {
+    pub fn get(&self, index : usize) -> &Vec<T> {
         &self.0[index].0
     }
 
-    /// Returns a reference to the data set at the specified index.
-    pub (crate) fn internal_get(&self, index : usize) -> &Vector {
-        &self.0[index]
+    /// Stacks the data sets in the specified range into the rows of a matrix.
+    pub (crate) fn internal_batch(&self, range : ops::Range<usize>) -> Matrix<T> {
+        Matrix::from_rows(&self.0[range])
+    }
+
+    /// Stacks the data sets at the specified indices into the rows of a matrix, in order.
+    pub (crate) fn internal_gather(&self, indices : &[usize]) -> Matrix<T> {
+        let cols = self.entries_per_set();
+        let mut values = AlgVec::with_capacity(indices.len() * cols);
+
+        for index in indices {
+            values.extend(self.0[*index].iter());
+        }
+
+        Matrix::new(indices.len(), cols, values)
     }
 
     /// Saves the data set to a CSV file.
-    pub fn save(&self, path : path::PathBuf) -> Result<(), String> {
-        let mut writer = match csv::Writer::from_path(&path) {
-                Ok(writer) => Ok(writer),
-                Err(error) => Err(String::from(error.to_string()))
-            }?;
+    pub fn save(&self, path : path::PathBuf) -> Result<(), NetworkError> {
+        let mut writer = csv::Writer::from_path(&path)?;
 
         for set in &self.0 {
             let string_array = set.iter().map(|x| x.to_string());
-            writer.write_record(string_array).unwrap();
+            writer.write_record(string_array)?;
         }
-        writer.flush().unwrap();
+        writer.flush()?;
 
         Ok(())
     }
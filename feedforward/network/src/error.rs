@@ -0,0 +1,90 @@
+//! The error type returned by the crate.
+
+use std::error;
+use std::fmt;
+use std::io;
+
+/// An error from building, training, saving or loading a network, or from reading or writing a
+/// data set.
+#[derive(Debug)]
+pub enum NetworkError {
+    /// Reading or writing a file failed.
+    Io(io::Error),
+    /// A file could not be parsed. The row and column count from one, with the row being the line
+    /// of the file and the column the entry on that line (including any label at its start). The
+    /// column is zero if the error is not at a particular entry.
+    Parse {
+        row : usize,
+        column : usize,
+        message : String,
+    },
+    /// The size of some data did not match what was required, such as a data set whose sets have a
+    /// different number of entries to the layer they are fed to.
+    ShapeMismatch {
+        context : String,
+        expected : usize,
+        actual : usize,
+    },
+    /// A network, optimizer or training run was configured in a way that is not supported, such as
+    /// a hidden layer with a softmax activation function.
+    InvalidConfig(String),
+}
+
+impl NetworkError {
+    /// Creates a parse error at the specified location.
+    pub (crate) fn parse(row : usize, column : usize, message : impl Into<String>) -> NetworkError {
+        NetworkError::Parse { row, column, message : message.into() }
+    }
+
+    /// Creates a shape mismatch error, where the context describes what was being measured.
+    pub (crate) fn shape_mismatch(context : impl Into<String>, expected : usize, actual : usize) -> NetworkError {
+        NetworkError::ShapeMismatch { context : context.into(), expected, actual }
+    }
+
+    /// Creates an invalid configuration error.
+    pub (crate) fn invalid_config(message : impl Into<String>) -> NetworkError {
+        NetworkError::InvalidConfig(message.into())
+    }
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkError::Io(error) => write!(f, "I/O error: {}", error),
+            NetworkError::Parse { row, column : 0, message } => write!(f, "Parse error at row {}: {}", row, message),
+            NetworkError::Parse { row, column, message } => write!(f, "Parse error at row {}, column {}: {}", row, column, message),
+            NetworkError::ShapeMismatch { context, expected, actual } => write!(f, "Shape mismatch in {}: expected {}, found {}.", context, expected, actual),
+            NetworkError::InvalidConfig(message) => write!(f, "Invalid configuration: {}", message),
+        }
+    }
+}
+
+impl error::Error for NetworkError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            NetworkError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for NetworkError {
+    fn from(error : io::Error) -> NetworkError {
+        NetworkError::Io(error)
+    }
+}
+
+/// Errors from reading CSV files keep their location, while those from the underlying reader or
+/// writer are I/O errors.
+impl From<csv::Error> for NetworkError {
+    fn from(error : csv::Error) -> NetworkError {
+        let message = error.to_string();
+        let row = error.position().map_or(0, |position| position.line() as usize);
+
+        match error.into_kind() {
+            csv::ErrorKind::Io(error) => NetworkError::Io(error),
+            csv::ErrorKind::Utf8 { err, .. } => NetworkError::parse(row, err.field() + 1, message),
+            _ => NetworkError::parse(row, 0, message),
+        }
+    }
+}
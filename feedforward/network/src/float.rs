@@ -0,0 +1,131 @@
+//! The floating point precisions that networks can be built with. Networks use `f64` by default,
+//! while `f32` halves the memory used and doubles the number of values each SIMD instruction
+//! processes, at the cost of precision.
+
+use std::fmt;
+use std::iter;
+use std::num;
+use std::ops;
+use std::str;
+
+use crate::kernels::Kernels;
+
+/// A floating point type which networks, data sets and the linear algebra behind them can be
+/// built from. This is implemented for `f32` and `f64`, and cannot be implemented for other types,
+/// as each needs its own linear algebra kernels.
+pub trait Float :
+    Kernels + Copy + Default + PartialEq + PartialOrd + fmt::Debug + fmt::Display
+    + str::FromStr<Err = num::ParseFloatError> + iter::Sum + Send + Sync + 'static
+    + ops::Add<Output = Self> + ops::Sub<Output = Self> + ops::Mul<Output = Self> + ops::Div<Output = Self>
+    + ops::Neg<Output = Self> + ops::AddAssign + ops::SubAssign + ops::MulAssign + ops::DivAssign {
+
+    const ZERO : Self;
+    const ONE : Self;
+    const NEG_INFINITY : Self;
+    /// The difference between one and the next largest representable value.
+    const EPSILON : Self;
+
+    /// Converts from a double precision value, rounding to the nearest representable value.
+    fn from_f64(value : f64) -> Self;
+
+    /// Converts to a double precision value, which is exact.
+    fn to_f64(self) -> f64;
+
+    fn exp(self) -> Self;
+    fn exp_m1(self) -> Self;
+    fn ln(self) -> Self;
+    fn ln_1p(self) -> Self;
+    fn sqrt(self) -> Self;
+    fn tanh(self) -> Self;
+    fn powi(self, exponent : i32) -> Self;
+    fn abs(self) -> Self;
+    fn signum(self) -> Self;
+    fn max(self, other : Self) -> Self;
+    fn clamp(self, min : Self, max : Self) -> Self;
+    fn is_infinite(self) -> bool;
+}
+
+/// Implements `Float` by forwarding to the inherent methods of the type.
+macro_rules! impl_float {
+    ($float : ident) => {
+        impl Float for $float {
+            const ZERO : $float = 0.0;
+            const ONE : $float = 1.0;
+            const NEG_INFINITY : $float = $float::NEG_INFINITY;
+            const EPSILON : $float = $float::EPSILON;
+
+            #[inline]
+            fn from_f64(value : f64) -> $float {
+                value as $float
+            }
+
+            #[inline]
+            fn to_f64(self) -> f64 {
+                self.into()
+            }
+
+            #[inline]
+            fn exp(self) -> $float {
+                $float::exp(self)
+            }
+
+            #[inline]
+            fn exp_m1(self) -> $float {
+                $float::exp_m1(self)
+            }
+
+            #[inline]
+            fn ln(self) -> $float {
+                $float::ln(self)
+            }
+
+            #[inline]
+            fn ln_1p(self) -> $float {
+                $float::ln_1p(self)
+            }
+
+            #[inline]
+            fn sqrt(self) -> $float {
+                $float::sqrt(self)
+            }
+
+            #[inline]
+            fn tanh(self) -> $float {
+                $float::tanh(self)
+            }
+
+            #[inline]
+            fn powi(self, exponent : i32) -> $float {
+                $float::powi(self, exponent)
+            }
+
+            #[inline]
+            fn abs(self) -> $float {
+                $float::abs(self)
+            }
+
+            #[inline]
+            fn signum(self) -> $float {
+                $float::signum(self)
+            }
+
+            #[inline]
+            fn max(self, other : $float) -> $float {
+                $float::max(self, other)
+            }
+
+            #[inline]
+            fn clamp(self, min : $float, max : $float) -> $float {
+                $float::clamp(self, min, max)
+            }
+
+            #[inline]
+            fn is_infinite(self) -> bool {
+                $float::is_infinite(self)
+            }
+        }
+    };
+}
+
+impl_float!(f64);
+impl_float!(f32);
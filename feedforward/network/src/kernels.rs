@@ -0,0 +1,531 @@
+//! The inner loops of the linear algebra in `algebra`, operating on slices. With the `simd`
+//! feature enabled these use explicit SIMD instructions, choosing at runtime between AVX2 with FMA
+//! and SSE2 on x86-64, and otherwise fall back to the scalar loops. Results with SIMD can differ
+//! from the scalar loops by rounding, as the order of summation and use of fused multiply-add
+//! differ. Each floating point type provides its kernels through the `Kernels` trait.
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+use simd::{double, single};
+
+#[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+use scalar as double;
+#[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+use scalar as single;
+
+#[cfg(feature = "blas")]
+use crate::blas;
+
+/// The kernels for one floating point type. This is public so that it can bound `Float`, but is in
+/// a private module, so can neither be implemented nor called outside the crate.
+pub trait Kernels : Sized {
+    /// Calculates the dot product of two slices of equal length.
+    fn dot(first : &[Self], second : &[Self]) -> Self;
+
+    /// Adds `scale` times `x` to `y` in place.
+    fn axpy(scale : Self, x : &[Self], y : &mut [Self]);
+
+    /// Writes the component-wise sum of two slices to the output.
+    fn add(first : &[Self], second : &[Self], output : &mut [Self]);
+
+    /// Writes the component-wise difference of two slices to the output.
+    fn sub(first : &[Self], second : &[Self], output : &mut [Self]);
+
+    /// Writes the component-wise product of two slices to the output.
+    fn mul(first : &[Self], second : &[Self], output : &mut [Self]);
+
+    /// Writes the slice multiplied by `scale` to the output.
+    fn scale(scale : Self, x : &[Self], output : &mut [Self]);
+
+    /// Writes the product of two matrices, either of which may be transposed, to the output. See
+    /// `blas::dgemm`.
+    #[cfg(feature = "blas")]
+    #[allow(clippy::too_many_arguments)]
+    fn gemm(
+        transpose_first : bool, transpose_second : bool,
+        rows : usize, cols : usize, inner : usize,
+        first : &[Self], second : &[Self], output : &mut [Self]);
+}
+
+/// Implements `Kernels` for a floating point type using the functions in the module for its
+/// precision.
+macro_rules! impl_kernels {
+    ($float : ident, $module : ident, $gemm : ident) => {
+        impl Kernels for $float {
+            #[inline]
+            fn dot(first : &[$float], second : &[$float]) -> $float {
+                $module::dot(first, second)
+            }
+
+            #[inline]
+            fn axpy(scale : $float, x : &[$float], y : &mut [$float]) {
+                $module::axpy(scale, x, y)
+            }
+
+            #[inline]
+            fn add(first : &[$float], second : &[$float], output : &mut [$float]) {
+                $module::add(first, second, output)
+            }
+
+            #[inline]
+            fn sub(first : &[$float], second : &[$float], output : &mut [$float]) {
+                $module::sub(first, second, output)
+            }
+
+            #[inline]
+            fn mul(first : &[$float], second : &[$float], output : &mut [$float]) {
+                $module::mul(first, second, output)
+            }
+
+            #[inline]
+            fn scale(scale : $float, x : &[$float], output : &mut [$float]) {
+                $module::scale(scale, x, output)
+            }
+
+            #[cfg(feature = "blas")]
+            fn gemm(
+                transpose_first : bool, transpose_second : bool,
+                rows : usize, cols : usize, inner : usize,
+                first : &[$float], second : &[$float], output : &mut [$float]) {
+                blas::$gemm(transpose_first, transpose_second, rows, cols, inner, first, second, output)
+            }
+        }
+    };
+}
+
+impl_kernels!(f64, double, dgemm);
+impl_kernels!(f32, single, sgemm);
+
+/// The portable implementations, generic over the floating point type, which are used without the
+/// `simd` feature and as the reference the SIMD implementations are checked against.
+pub mod scalar {
+    use std::iter::Sum;
+    use std::ops::{Add, Sub, Mul, AddAssign};
+
+    /// Calculates the dot product of two slices of equal length.
+    pub fn dot<T>(first : &[T], second : &[T]) -> T
+        where T : Copy + Mul<Output = T> + Sum {
+        first
+        .iter()
+        .zip(second.iter())
+        .map(|(a, b)| *a * *b)
+        .sum()
+    }
+
+    /// Adds `scale` times `x` to `y` in place.
+    pub fn axpy<T>(scale : T, x : &[T], y : &mut [T])
+        where T : Copy + Mul<Output = T> + AddAssign {
+        for (y, x) in y.iter_mut().zip(x.iter()) {
+            *y += scale * *x;
+        }
+    }
+
+    /// Writes the component-wise sum of two slices to the output.
+    pub fn add<T>(first : &[T], second : &[T], output : &mut [T])
+        where T : Copy + Add<Output = T> {
+        for ((o, a), b) in output.iter_mut().zip(first.iter()).zip(second.iter()) {
+            *o = *a + *b;
+        }
+    }
+
+    /// Writes the component-wise difference of two slices to the output.
+    pub fn sub<T>(first : &[T], second : &[T], output : &mut [T])
+        where T : Copy + Sub<Output = T> {
+        for ((o, a), b) in output.iter_mut().zip(first.iter()).zip(second.iter()) {
+            *o = *a - *b;
+        }
+    }
+
+    /// Writes the component-wise product of two slices to the output.
+    pub fn mul<T>(first : &[T], second : &[T], output : &mut [T])
+        where T : Copy + Mul<Output = T> {
+        for ((o, a), b) in output.iter_mut().zip(first.iter()).zip(second.iter()) {
+            *o = *a * *b;
+        }
+    }
+
+    /// Writes the slice multiplied by `scale` to the output.
+    pub fn scale<T>(scale : T, x : &[T], output : &mut [T])
+        where T : Copy + Mul<Output = T> {
+        for (o, x) in output.iter_mut().zip(x.iter()) {
+            *o = scale * *x;
+        }
+    }
+}
+
+/// The SIMD implementations, in a module for each precision. Each processes as many whole vector
+/// registers as fit in the slices, then finishes the remainder with the scalar loop. The lengths of
+/// the slices are always checked, as the loads and stores are unchecked. The AVX2 functions must
+/// only be called when `has_avx2_fma` returns true, while SSE2 is part of the x86-64 baseline so is
+/// always available.
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+pub mod simd {
+    use std::arch::x86_64::*;
+
+    /// Whether the processor supports AVX2 and FMA. The detection macro caches its result, so this
+    /// is cheap to call for every operation.
+    #[inline]
+    pub fn has_avx2_fma() -> bool {
+        is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma")
+    }
+
+    /// Adds the lanes of a register of doubles together.
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn sum_pd_256(sum : __m256d) -> f64 {
+        sum_pd_128(_mm_add_pd(_mm256_castpd256_pd128(sum), _mm256_extractf128_pd(sum, 1)))
+    }
+
+    /// Adds the lanes of a register of doubles together.
+    unsafe fn sum_pd_128(sum : __m128d) -> f64 {
+        _mm_cvtsd_f64(_mm_add_sd(sum, _mm_unpackhi_pd(sum, sum)))
+    }
+
+    /// Adds the lanes of a register of singles together.
+    #[target_feature(enable = "avx2,fma")]
+    unsafe fn sum_ps_256(sum : __m256) -> f32 {
+        sum_ps_128(_mm_add_ps(_mm256_castps256_ps128(sum), _mm256_extractf128_ps(sum, 1)))
+    }
+
+    /// Adds the lanes of a register of singles together.
+    unsafe fn sum_ps_128(sum : __m128) -> f32 {
+        let halves = _mm_add_ps(sum, _mm_movehl_ps(sum, sum));
+        _mm_cvtss_f32(_mm_add_ss(halves, _mm_shuffle_ps(halves, halves, 1)))
+    }
+
+    /// Generates a safe function which calls the AVX2 implementation if it is supported, and the
+    /// SSE2 implementation otherwise.
+    macro_rules! dispatch {
+        ($(#[$doc : meta])* $name : ident, $avx2 : ident, $sse2 : ident, ($($arg : ident : $type : ty),*) $(-> $output : ty)?) => {
+            $(#[$doc])*
+            #[inline]
+            pub fn $name($($arg : $type),*) $(-> $output)? {
+                if has_avx2_fma() {
+                    unsafe { $avx2($($arg),*) }
+                }
+                else {
+                    unsafe { $sse2($($arg),*) }
+                }
+            }
+        };
+    }
+
+    /// Generates the AVX2 and SSE2 versions of a component-wise binary operation.
+    macro_rules! component_wise {
+        ($float : ident, $avx2 : ident, $sse2 : ident, [$lanes_256 : literal, $load_256 : ident, $store_256 : ident, $op_256 : ident], [$lanes_128 : literal, $load_128 : ident, $store_128 : ident, $op_128 : ident], $scalar : path) => {
+            #[target_feature(enable = "avx2,fma")]
+            pub (crate) unsafe fn $avx2(first : &[$float], second : &[$float], output : &mut [$float]) {
+                assert!(first.len() == second.len() && first.len() == output.len());
+                let len = first.len();
+                let (a, b, o) = (first.as_ptr(), second.as_ptr(), output.as_mut_ptr());
+
+                let mut i = 0;
+                while i + $lanes_256 <= len {
+                    $store_256(o.add(i), $op_256($load_256(a.add(i)), $load_256(b.add(i))));
+                    i += $lanes_256;
+                }
+
+                $scalar(&first[i..], &second[i..], &mut output[i..]);
+            }
+
+            pub (crate) unsafe fn $sse2(first : &[$float], second : &[$float], output : &mut [$float]) {
+                assert!(first.len() == second.len() && first.len() == output.len());
+                let len = first.len();
+                let (a, b, o) = (first.as_ptr(), second.as_ptr(), output.as_mut_ptr());
+
+                let mut i = 0;
+                while i + $lanes_128 <= len {
+                    $store_128(o.add(i), $op_128($load_128(a.add(i)), $load_128(b.add(i))));
+                    i += $lanes_128;
+                }
+
+                $scalar(&first[i..], &second[i..], &mut output[i..]);
+            }
+        };
+    }
+
+    /// Generates the module of kernels for one precision, from the intrinsics operating on 256-bit
+    /// AVX registers and 128-bit SSE registers of that precision.
+    macro_rules! kernels {
+        (
+            $module : ident, $float : ident,
+            avx2 [$lanes_256 : literal, $zero_256 : ident, $splat_256 : ident, $load_256 : ident, $store_256 : ident, $fmadd_256 : ident, $add_256 : ident, $sub_256 : ident, $mul_256 : ident, $sum_256 : ident],
+            sse2 [$lanes_128 : literal, $zero_128 : ident, $splat_128 : ident, $load_128 : ident, $store_128 : ident, $add_128 : ident, $sub_128 : ident, $mul_128 : ident, $sum_128 : ident]
+        ) => {
+            pub mod $module {
+                use std::arch::x86_64::*;
+
+                use super::{has_avx2_fma, $sum_256, $sum_128};
+                use crate::kernels::scalar;
+
+                dispatch!(
+                    /// Calculates the dot product of two slices of equal length.
+                    dot, dot_avx2, dot_sse2, (first : &[$float], second : &[$float]) -> $float
+                );
+                dispatch!(
+                    /// Adds `scale` times `x` to `y` in place.
+                    axpy, axpy_avx2, axpy_sse2, (scale : $float, x : &[$float], y : &mut [$float])
+                );
+                dispatch!(
+                    /// Writes the component-wise sum of two slices to the output.
+                    add, add_avx2, add_sse2, (first : &[$float], second : &[$float], output : &mut [$float])
+                );
+                dispatch!(
+                    /// Writes the component-wise difference of two slices to the output.
+                    sub, sub_avx2, sub_sse2, (first : &[$float], second : &[$float], output : &mut [$float])
+                );
+                dispatch!(
+                    /// Writes the component-wise product of two slices to the output.
+                    mul, mul_avx2, mul_sse2, (first : &[$float], second : &[$float], output : &mut [$float])
+                );
+                dispatch!(
+                    /// Writes the slice multiplied by `scale` to the output.
+                    scale, scale_avx2, scale_sse2, (scale : $float, x : &[$float], output : &mut [$float])
+                );
+
+                #[target_feature(enable = "avx2,fma")]
+                pub (crate) unsafe fn dot_avx2(first : &[$float], second : &[$float]) -> $float {
+                    assert!(first.len() == second.len());
+                    let len = first.len();
+                    let (a, b) = (first.as_ptr(), second.as_ptr());
+
+                    // Two accumulators hide the latency of the fused multiply-add.
+                    let mut sum_0 = $zero_256();
+                    let mut sum_1 = $zero_256();
+                    let mut i = 0;
+                    while i + 2 * $lanes_256 <= len {
+                        sum_0 = $fmadd_256($load_256(a.add(i)), $load_256(b.add(i)), sum_0);
+                        sum_1 = $fmadd_256($load_256(a.add(i + $lanes_256)), $load_256(b.add(i + $lanes_256)), sum_1);
+                        i += 2 * $lanes_256;
+                    }
+                    if i + $lanes_256 <= len {
+                        sum_0 = $fmadd_256($load_256(a.add(i)), $load_256(b.add(i)), sum_0);
+                        i += $lanes_256;
+                    }
+
+                    $sum_256($add_256(sum_0, sum_1)) + scalar::dot(&first[i..], &second[i..])
+                }
+
+                pub (crate) unsafe fn dot_sse2(first : &[$float], second : &[$float]) -> $float {
+                    assert!(first.len() == second.len());
+                    let len = first.len();
+                    let (a, b) = (first.as_ptr(), second.as_ptr());
+
+                    let mut sum_0 = $zero_128();
+                    let mut sum_1 = $zero_128();
+                    let mut i = 0;
+                    while i + 2 * $lanes_128 <= len {
+                        sum_0 = $add_128(sum_0, $mul_128($load_128(a.add(i)), $load_128(b.add(i))));
+                        sum_1 = $add_128(sum_1, $mul_128($load_128(a.add(i + $lanes_128)), $load_128(b.add(i + $lanes_128))));
+                        i += 2 * $lanes_128;
+                    }
+                    if i + $lanes_128 <= len {
+                        sum_0 = $add_128(sum_0, $mul_128($load_128(a.add(i)), $load_128(b.add(i))));
+                        i += $lanes_128;
+                    }
+
+                    $sum_128($add_128(sum_0, sum_1)) + scalar::dot(&first[i..], &second[i..])
+                }
+
+                #[target_feature(enable = "avx2,fma")]
+                pub (crate) unsafe fn axpy_avx2(scale : $float, x : &[$float], y : &mut [$float]) {
+                    assert!(x.len() == y.len());
+                    let len = x.len();
+                    let (xp, yp) = (x.as_ptr(), y.as_mut_ptr());
+                    let factor = $splat_256(scale);
+
+                    let mut i = 0;
+                    while i + $lanes_256 <= len {
+                        $store_256(yp.add(i), $fmadd_256(factor, $load_256(xp.add(i)), $load_256(yp.add(i))));
+                        i += $lanes_256;
+                    }
+
+                    scalar::axpy(scale, &x[i..], &mut y[i..]);
+                }
+
+                pub (crate) unsafe fn axpy_sse2(scale : $float, x : &[$float], y : &mut [$float]) {
+                    assert!(x.len() == y.len());
+                    let len = x.len();
+                    let (xp, yp) = (x.as_ptr(), y.as_mut_ptr());
+                    let factor = $splat_128(scale);
+
+                    let mut i = 0;
+                    while i + $lanes_128 <= len {
+                        $store_128(yp.add(i), $add_128($load_128(yp.add(i)), $mul_128(factor, $load_128(xp.add(i)))));
+                        i += $lanes_128;
+                    }
+
+                    scalar::axpy(scale, &x[i..], &mut y[i..]);
+                }
+
+                component_wise!($float, add_avx2, add_sse2, [$lanes_256, $load_256, $store_256, $add_256], [$lanes_128, $load_128, $store_128, $add_128], scalar::add);
+                component_wise!($float, sub_avx2, sub_sse2, [$lanes_256, $load_256, $store_256, $sub_256], [$lanes_128, $load_128, $store_128, $sub_128], scalar::sub);
+                component_wise!($float, mul_avx2, mul_sse2, [$lanes_256, $load_256, $store_256, $mul_256], [$lanes_128, $load_128, $store_128, $mul_128], scalar::mul);
+
+                #[target_feature(enable = "avx2,fma")]
+                pub (crate) unsafe fn scale_avx2(scale : $float, x : &[$float], output : &mut [$float]) {
+                    assert!(x.len() == output.len());
+                    let len = x.len();
+                    let (xp, o) = (x.as_ptr(), output.as_mut_ptr());
+                    let factor = $splat_256(scale);
+
+                    let mut i = 0;
+                    while i + $lanes_256 <= len {
+                        $store_256(o.add(i), $mul_256(factor, $load_256(xp.add(i))));
+                        i += $lanes_256;
+                    }
+
+                    scalar::scale(scale, &x[i..], &mut output[i..]);
+                }
+
+                pub (crate) unsafe fn scale_sse2(scale : $float, x : &[$float], output : &mut [$float]) {
+                    assert!(x.len() == output.len());
+                    let len = x.len();
+                    let (xp, o) = (x.as_ptr(), output.as_mut_ptr());
+                    let factor = $splat_128(scale);
+
+                    let mut i = 0;
+                    while i + $lanes_128 <= len {
+                        $store_128(o.add(i), $mul_128(factor, $load_128(xp.add(i))));
+                        i += $lanes_128;
+                    }
+
+                    scalar::scale(scale, &x[i..], &mut output[i..]);
+                }
+            }
+        };
+    }
+
+    kernels!(
+        double, f64,
+        avx2 [4, _mm256_setzero_pd, _mm256_set1_pd, _mm256_loadu_pd, _mm256_storeu_pd, _mm256_fmadd_pd, _mm256_add_pd, _mm256_sub_pd, _mm256_mul_pd, sum_pd_256],
+        sse2 [2, _mm_setzero_pd, _mm_set1_pd, _mm_loadu_pd, _mm_storeu_pd, _mm_add_pd, _mm_sub_pd, _mm_mul_pd, sum_pd_128]
+    );
+
+    kernels!(
+        single, f32,
+        avx2 [8, _mm256_setzero_ps, _mm256_set1_ps, _mm256_loadu_ps, _mm256_storeu_ps, _mm256_fmadd_ps, _mm256_add_ps, _mm256_sub_ps, _mm256_mul_ps, sum_ps_256],
+        sse2 [4, _mm_setzero_ps, _mm_set1_ps, _mm_loadu_ps, _mm_storeu_ps, _mm_add_ps, _mm_sub_ps, _mm_mul_ps, sum_ps_128]
+    );
+}
+
+#[cfg(all(test, feature = "simd", target_arch = "x86_64"))]
+mod tests {
+    use super::{scalar, simd};
+
+    /// The lengths compared, which cover empty slices, slices shorter than a register of either
+    /// width and remainders after whole registers.
+    const LENGTHS : [usize; 6] = [0, 1, 3, 7, 9, 17];
+
+    /// Returns the variants of a kernel to compare with the scalar loop: the function which
+    /// dispatches at runtime, the SSE2 version and, if the processor supports it, the AVX2 version.
+    fn variants<K>(dispatch : K, sse2 : K, avx2 : K) -> Vec<K> {
+        if simd::has_avx2_fma() {
+            vec![dispatch, sse2, avx2]
+        }
+        else {
+            vec![dispatch, sse2]
+        }
+    }
+
+    /// Generates the tests for the kernels of one precision, which may differ from the scalar
+    /// loops by `tolerance` relative to the magnitude of the values summed.
+    macro_rules! tests {
+        ($module : ident, $float : ident, $tolerance : literal) => {
+            mod $module {
+                use super::{LENGTHS, variants, scalar};
+                use super::simd::$module::*;
+
+                type Binary = unsafe fn(&[$float], &[$float], &mut [$float]);
+                type Scaled = unsafe fn($float, &[$float], &mut [$float]);
+
+                fn values(len : usize, offset : $float) -> Vec<$float> {
+                    (0..len).map(|i| 3.0 * ((i as $float + offset) * 0.7).sin()).collect()
+                }
+
+                fn assert_close(expected : $float, actual : $float, magnitude : $float) {
+                    assert!((expected - actual).abs() <= $tolerance * (1.0 + magnitude), "expected {} but found {}", expected, actual);
+                }
+
+                fn assert_all_close(expected : &[$float], actual : &[$float]) {
+                    assert_eq!(expected.len(), actual.len());
+                    for (expected, actual) in expected.iter().zip(actual) {
+                        assert_close(*expected, *actual, expected.abs());
+                    }
+                }
+
+                #[test]
+                fn dot_matches_scalar() {
+                    let kernels = variants::<unsafe fn(&[$float], &[$float]) -> $float>(dot, dot_sse2, dot_avx2);
+                    for len in LENGTHS {
+                        let (first, second) = (values(len, 0.0), values(len, 0.5));
+                        let expected = scalar::dot(&first, &second);
+                        let magnitude = first.iter().zip(&second).map(|(a, b)| (a * b).abs()).sum();
+                        for kernel in &kernels {
+                            assert_close(expected, unsafe { kernel(&first, &second) }, magnitude);
+                        }
+                    }
+                }
+
+                #[test]
+                fn axpy_matches_scalar() {
+                    let kernels = variants::<Scaled>(axpy, axpy_sse2, axpy_avx2);
+                    for len in LENGTHS {
+                        let x = values(len, 0.0);
+                        let mut expected = values(len, 0.5);
+                        scalar::axpy(-1.5, &x, &mut expected);
+                        for kernel in &kernels {
+                            let mut y = values(len, 0.5);
+                            unsafe { kernel(-1.5, &x, &mut y) };
+                            assert_all_close(&expected, &y);
+                        }
+                    }
+                }
+
+                #[test]
+                fn scale_matches_scalar() {
+                    let kernels = variants::<Scaled>(scale, scale_sse2, scale_avx2);
+                    for len in LENGTHS {
+                        let x = values(len, 0.0);
+                        let mut expected = vec![0.0; len];
+                        scalar::scale(0.75, &x, &mut expected);
+                        for kernel in &kernels {
+                            let mut output = vec![0.0; len];
+                            unsafe { kernel(0.75, &x, &mut output) };
+                            assert_all_close(&expected, &output);
+                        }
+                    }
+                }
+
+                fn check_binary(scalar_kernel : fn(&[$float], &[$float], &mut [$float]), kernels : Vec<Binary>) {
+                    for len in LENGTHS {
+                        let (first, second) = (values(len, 0.0), values(len, 0.5));
+                        let mut expected = vec![0.0; len];
+                        scalar_kernel(&first, &second, &mut expected);
+                        for kernel in &kernels {
+                            let mut output = vec![0.0; len];
+                            unsafe { kernel(&first, &second, &mut output) };
+                            assert_all_close(&expected, &output);
+                        }
+                    }
+                }
+
+                #[test]
+                fn add_matches_scalar() {
+                    check_binary(scalar::add, variants::<Binary>(add, add_sse2, add_avx2));
+                }
+
+                #[test]
+                fn sub_matches_scalar() {
+                    check_binary(scalar::sub, variants::<Binary>(sub, sub_sse2, sub_avx2));
+                }
+
+                #[test]
+                fn mul_matches_scalar() {
+                    check_binary(scalar::mul, variants::<Binary>(mul, mul_sse2, mul_avx2));
+                }
+            }
+        };
+    }
+
+    tests!(double, f64, 1e-12);
+    tests!(single, f32, 1e-5);
+}
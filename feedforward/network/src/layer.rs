@@ -0,0 +1,1034 @@
+use std::fmt;
+
+use crate::algebra::{Vector, Matrix};
+use crate::activation;
+use crate::activation::Activation;
+use crate::error::NetworkError;
+use crate::float::Float;
+use crate::network::Reduction;
+use crate::save::{Lines, Entries, join, expect_line, parse, parse_all};
+use crate::weights_gen;
+use crate::weights_gen::{ChaCha8Rng, Initialiser};
+
+/// What a set of a layer's parameters is used for, which decides how it is regularised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParameterKind {
+    /// Weights multiplying the inputs to the layer, which are penalised by regularisation.
+    Weights,
+    /// Biases added to the outputs of the layer, and other offsets and scales such as those of
+    /// batch normalisation, which are only penalised if this is configured.
+    Biases,
+}
+
+/// What a layer is given while training besides its input: a random number generator, and a way
+/// to combine statistics with the other shares of the mini-batch, which are fed forward and
+/// backpropagated in parallel through other copies of the layers.
+pub struct TrainContext<'a> {
+    rng : &'a mut ChaCha8Rng,
+    shares : Option<(&'a Reduction, usize)>,
+}
+
+impl<'a> TrainContext<'a> {
+    /// Creates a context for training on a whole mini-batch at once, drawing from the generator.
+    pub fn new(rng : &'a mut ChaCha8Rng) -> TrainContext<'a> {
+        TrainContext { rng, shares : None }
+    }
+
+    /// Creates a context for training on the share with the specified position of a mini-batch.
+    pub (crate) fn share(rng : &'a mut ChaCha8Rng, reduction : &'a Reduction, share : usize) -> TrainContext<'a> {
+        TrainContext { rng, shares : Some((reduction, share)) }
+    }
+
+    /// Returns the reduction combining the shares, if the mini-batch is split between threads.
+    pub (crate) fn reduction(&self) -> Option<&'a Reduction> {
+        self.shares.map(|(reduction, _)| reduction)
+    }
+
+    /// Returns the random number generator, which is split from the network's so that training
+    /// stays reproducible.
+    pub fn rng(&mut self) -> &mut ChaCha8Rng {
+        self.rng
+    }
+
+    /// Sums the values element-wise across every share of the mini-batch, waiting for the other
+    /// shares to reach the same point. Every share must call this the same number of times with
+    /// the same number of values, so whether it is called must not depend on the share's inputs.
+    pub fn sum(&mut self, values : Vec<f64>) -> Vec<f64> {
+        match self.shares {
+            Some((reduction, share)) => reduction.sum(share, values),
+            None => values
+        }
+    }
+}
+
+/// A layer of a network, which transforms a batch of inputs, stacked as the rows of a matrix, into
+/// a batch of outputs. Networks are a sequence of layers, each fed the outputs of the one before.
+///
+/// While training, each thread works on its own copy of the layers, feeding its share of a
+/// mini-batch forward with `forward_train` and then back with `backward`. The layers keep the
+/// gradient of the cost with respect to each of their sets of parameters, which the network sums
+/// across the threads and passes to the optimizer along with the parameters of the original layers.
+/// Layers whose outputs depend on statistics of the whole mini-batch combine them across the
+/// threads with `TrainContext::sum`, so any other state they update while training is the same in
+/// every copy, and is copied back to the original layers from the first. The network panics if a
+/// layer returns outputs or input gradients of a different size to the one it declares.
+pub trait Layer<T : Float = f64> : fmt::Debug + Send + Sync {
+    /// Returns the name of the layer, which identifies it in saved networks.
+    fn name(&self) -> &str;
+
+    /// Returns the number of entries in each input to the layer.
+    fn inputs(&self) -> usize;
+
+    /// Returns the number of entries in each output of the layer.
+    fn outputs(&self) -> usize;
+
+    /// Feeds forward a batch of inputs, for testing or prediction.
+    fn forward(&self, input : &Matrix<T>) -> Matrix<T>;
+
+    /// Feeds forward a batch of inputs while training, keeping whatever is needed to backpropagate
+    /// it. Layers which are random while training, such as dropout, draw from the context's
+    /// generator.
+    fn forward_train(&mut self, input : &Matrix<T>, context : &mut TrainContext) -> Matrix<T>;
+
+    /// Backpropagates the batch last fed forward by `forward_train`, given the derivative of the
+    /// cost with respect to each of its outputs. This sets the gradients of the layer's parameters,
+    /// summed over the batch, and returns the derivative of the cost with respect to each of its
+    /// inputs if `propagate` is set. This is not set for the first layer of the network.
+    fn backward(&mut self, output_gradient : &Matrix<T>, propagate : bool, context : &mut TrainContext) -> Option<Matrix<T>>;
+
+    /// Returns each set of the layer's trainable parameters.
+    fn parameters(&self) -> Vec<&[T]> {
+        Vec::new()
+    }
+
+    /// Returns each set of the layer's trainable parameters, for the optimizer to update.
+    fn parameters_mut(&mut self) -> Vec<&mut [T]> {
+        Vec::new()
+    }
+
+    /// Returns the gradient of the cost with respect to each set of parameters, in the same order
+    /// as `parameters`, from the last call to `backward`.
+    fn gradients(&self) -> Vec<&[T]> {
+        Vec::new()
+    }
+
+    /// Returns each set of the layer's state which is updated by `forward_train` rather than by the
+    /// optimizer, such as the running statistics of batch normalisation.
+    fn state(&self) -> Vec<&[T]> {
+        Vec::new()
+    }
+
+    /// Returns each set of the layer's state, in the same order as `state`, for the network to
+    /// replace with the state of the first thread's copy.
+    fn state_mut(&mut self) -> Vec<&mut [T]> {
+        Vec::new()
+    }
+
+    /// Returns the kind of each set of parameters, in the same order as `parameters`. By default
+    /// every set is treated as weights.
+    fn parameter_kinds(&self) -> Vec<ParameterKind> {
+        vec![ParameterKind::Weights; self.parameters().len()]
+    }
+
+    /// Rescales the incoming weights of any neuron whose Euclidean norm exceeds `max_norm` so
+    /// that it is equal to it. Layers without weights have nothing to constrain.
+    fn constrain_norms(&mut self, _max_norm : f64) {}
+
+    /// Returns the activation function applied by the layer, if it is an activation layer.
+    fn activation(&self) -> Option<Activation> {
+        None
+    }
+
+    /// Writes the layer in the format of saved networks, starting with a line labelled by its
+    /// name. Layers defined outside this crate cannot be saved, as they cannot be loaded.
+    fn serialise(&self) -> Result<String, NetworkError> {
+        Err(NetworkError::invalid_config(format!("Cannot save a network with the custom layer {}.", self.name())))
+    }
+
+    /// Copies the layer into a new box, so that networks can be cloned.
+    fn clone_box(&self) -> Box<dyn Layer<T>>;
+}
+
+impl<T : Float> Clone for Box<dyn Layer<T>> {
+    fn clone(&self) -> Box<dyn Layer<T>> {
+        self.clone_box()
+    }
+}
+
+/// Reads a layer written by `Layer::serialise`, for any of the layers defined in this crate.
+pub (crate) fn deserialise<T : Float>(lines : &mut Lines) -> Result<Box<dyn Layer<T>>, NetworkError> {
+    let (label, entries) = lines.next_labelled("layer")?;
+
+    match label {
+        "dense" => Ok(Box::new(Dense::deserialise(entries, lines)?)),
+        "activation" => Ok(Box::new(ActivationLayer::deserialise(entries)?)),
+        "dropout" => Ok(Box::new(Dropout::deserialise(entries)?)),
+        "batchnorm" => Ok(Box::new(BatchNorm::deserialise(entries, lines)?)),
+        "layernorm" => Ok(Box::new(LayerNorm::deserialise(entries, lines)?)),
+        _ => Err(entries.error(format!("Saved network has an unknown layer {}.", label)))
+    }
+}
+
+/// A fully connected layer, where each output is a weighted sum of the inputs plus a bias.
+#[derive(Debug, Clone)]
+pub struct Dense<T = f64> {
+    weights : Matrix<T>,
+    biases : Vector<T>,
+    weights_init : Initialiser,
+    biases_init : Initialiser,
+    weights_gradient : Matrix<T>,
+    biases_gradient : Vector<T>,
+    input : Option<Matrix<T>>,
+}
+
+impl<T : Float> Dense<T> {
+    /// Creates a fully connected layer, generating its weights and biases with the provided
+    /// initialisers, which must be valid.
+    pub fn new(inputs : usize, outputs : usize, weights_init : Initialiser, biases_init : Initialiser, rng : &mut ChaCha8Rng) -> Result<Dense<T>, NetworkError> {
+        weights_init.validate()?;
+        biases_init.validate()?;
+
+        let weights = Matrix::new(outputs, inputs, weights_init.generate(outputs, inputs, inputs, outputs, rng));
+        let biases = Vector::new(biases_init.generate(1, outputs, inputs, outputs, rng));
+        Ok(Dense::from_parameters(weights, biases, weights_init, biases_init))
+    }
+
+    fn from_parameters(weights : Matrix<T>, biases : Vector<T>, weights_init : Initialiser, biases_init : Initialiser) -> Dense<T> {
+        Dense {
+            weights_gradient : Matrix::zeros(weights.rows(), weights.cols()),
+            biases_gradient : Vector::zeros(biases.len()),
+            weights,
+            biases,
+            weights_init,
+            biases_init,
+            input : None,
+        }
+    }
+
+    /// Returns the initialiser the weights were generated with.
+    pub fn weights_initialiser(&self) -> Initialiser {
+        self.weights_init
+    }
+
+    /// Returns the initialiser the biases were generated with.
+    pub fn biases_initialiser(&self) -> Initialiser {
+        self.biases_init
+    }
+
+    fn deserialise(mut entries : Entries, lines : &mut Lines) -> Result<Dense<T>, NetworkError> {
+        let inputs : usize = parse(&mut entries, "layer input size")?;
+        let outputs : usize = parse(&mut entries, "layer output size")?;
+        let weights_init : Initialiser = parse(&mut entries, "weights initialiser")?;
+        let biases_init : Initialiser = parse(&mut entries, "biases initialiser")?;
+        weights_init.validate()?;
+        biases_init.validate()?;
+
+        let count = match inputs.checked_mul(outputs) {
+            Some(count) => count,
+            None => return Err(entries.error(format!("Saved network has a dense layer of {} by {} neurons, which is too large.", inputs, outputs)))
+        };
+
+        let weights = parse_all(expect_line(lines, "weights")?, count, "weights")?;
+        let biases = parse_all(expect_line(lines, "biases")?, outputs, "biases")?;
+
+        Ok(Dense::from_parameters(Matrix::new(outputs, inputs, weights), Vector::new(biases), weights_init, biases_init))
+    }
+}
+
+impl<T : Float> Layer<T> for Dense<T> {
+    fn name(&self) -> &str {
+        "dense"
+    }
+
+    fn inputs(&self) -> usize {
+        self.weights.cols()
+    }
+
+    fn outputs(&self) -> usize {
+        self.weights.rows()
+    }
+
+    fn forward(&self, input : &Matrix<T>) -> Matrix<T> {
+        // With inputs as rows, the weights are applied as the product with their transpose.
+        let mut output = Matrix::multiply_transpose(input, &self.weights);
+        output.add_to_rows(&self.biases);
+        output
+    }
+
+    fn forward_train(&mut self, input : &Matrix<T>, _context : &mut TrainContext) -> Matrix<T> {
+        self.input = Some(input.clone());
+        self.forward(input)
+    }
+
+    fn backward(&mut self, output_gradient : &Matrix<T>, propagate : bool, _context : &mut TrainContext) -> Option<Matrix<T>> {
+        let input = match &self.input {
+            Some(input) => input,
+            None => panic!("Attempt to backpropagate through a layer which has not been fed forward.")
+        };
+
+        // Summed over the batch, the derivative with respect to the weights is the sum of the
+        // outer products of each output gradient with its input.
+        self.weights_gradient = Matrix::transpose_multiply(output_gradient, input);
+        self.biases_gradient = output_gradient.column_sums();
+
+        if propagate {
+            Some(Matrix::multiply(output_gradient, &self.weights))
+        }
+        else {
+            None
+        }
+    }
+
+    fn parameters(&self) -> Vec<&[T]> {
+        vec![self.weights.values(), &self.biases.0]
+    }
+
+    fn parameters_mut(&mut self) -> Vec<&mut [T]> {
+        vec![self.weights.values_mut(), &mut self.biases.0]
+    }
+
+    fn gradients(&self) -> Vec<&[T]> {
+        vec![self.weights_gradient.values(), &self.biases_gradient.0]
+    }
+
+    fn parameter_kinds(&self) -> Vec<ParameterKind> {
+        vec![ParameterKind::Weights, ParameterKind::Biases]
+    }
+
+    fn constrain_norms(&mut self, max_norm : f64) {
+        // Each row of the weights holds the incoming weights of one neuron.
+        let inputs = self.inputs();
+        let max_norm = T::from_f64(max_norm);
+        for neuron in self.weights.values_mut().chunks_mut(inputs) {
+            let norm = T::dot(neuron, neuron).sqrt();
+            if norm > max_norm {
+                let scale = max_norm / norm;
+                for weight in neuron.iter_mut() {
+                    *weight *= scale;
+                }
+            }
+        }
+    }
+
+    fn serialise(&self) -> Result<String, NetworkError> {
+        Ok(format!(
+            "dense {} {} {} {}\nweights {}\nbiases {}\n",
+            self.inputs(),
+            self.outputs(),
+            self.weights_init,
+            self.biases_init,
+            join(self.weights.values()),
+            join(&self.biases.0)
+        ))
+    }
+
+    fn clone_box(&self) -> Box<dyn Layer<T>> {
+        Box::new(self.clone())
+    }
+}
+
+/// A layer which applies an activation function to its inputs.
+#[derive(Debug, Clone)]
+pub struct ActivationLayer<T = f64> {
+    activ : Activation,
+    size : usize,
+    /// The outputs for softmax, whose derivative is given in terms of them, and otherwise the
+    /// inputs.
+    cache : Option<Matrix<T>>,
+}
+
+impl<T : Float> ActivationLayer<T> {
+    /// Creates a layer applying the activation function to inputs with `size` entries.
+    pub fn new(size : usize, activ : Activation) -> ActivationLayer<T> {
+        ActivationLayer {
+            activ,
+            size,
+            cache : None,
+        }
+    }
+
+    fn deserialise(mut entries : Entries) -> Result<ActivationLayer<T>, NetworkError> {
+        let size : usize = parse(&mut entries, "layer size")?;
+        let activ : Activation = parse(&mut entries, "activation function")?;
+
+        Ok(ActivationLayer::new(size, activ))
+    }
+}
+
+impl<T : Float> Layer<T> for ActivationLayer<T> {
+    fn name(&self) -> &str {
+        "activation"
+    }
+
+    fn inputs(&self) -> usize {
+        self.size
+    }
+
+    fn outputs(&self) -> usize {
+        self.size
+    }
+
+    fn forward(&self, input : &Matrix<T>) -> Matrix<T> {
+        input.map_rows(|row| self.activ.activate(row))
+    }
+
+    fn forward_train(&mut self, input : &Matrix<T>, _context : &mut TrainContext) -> Matrix<T> {
+        let output = self.forward(input);
+        self.cache = Some(if self.activ == Activation::Softmax { output.clone() } else { input.clone() });
+        output
+    }
+
+    fn backward(&mut self, output_gradient : &Matrix<T>, propagate : bool, _context : &mut TrainContext) -> Option<Matrix<T>> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => panic!("Attempt to backpropagate through a layer which has not been fed forward.")
+        };
+
+        if !propagate {
+            None
+        }
+        else if self.activ == Activation::Softmax {
+            Some(Matrix::zip_map_rows(cache, output_gradient, activation::softmax_backward))
+        }
+        else {
+            // The Jacobian of an element-wise activation is diagonal, so applying it is a
+            // component-wise product.
+            let derivative = cache.map_rows(|row| row.iter().map(|x| self.activ.derivative(*x)).collect());
+            Some(Matrix::hadamard(output_gradient, &derivative))
+        }
+    }
+
+    fn activation(&self) -> Option<Activation> {
+        Some(self.activ.clone())
+    }
+
+    fn serialise(&self) -> Result<String, NetworkError> {
+        if let Activation::Custom { name, .. } = self.activ {
+            return Err(NetworkError::invalid_config(format!("Cannot save a network with the custom activation function {}.", name)))
+        }
+
+        Ok(format!("activation {} {}\n", self.size, self.activ))
+    }
+
+    fn clone_box(&self) -> Box<dyn Layer<T>> {
+        Box::new(self.clone())
+    }
+}
+
+/// A layer which regularises the network by dropping each of its inputs with probability `rate`
+/// while training, scaling up the rest so that the expected value of each output is unchanged
+/// (inverted dropout). When testing or used for inference it passes its inputs through unchanged.
+#[derive(Debug, Clone)]
+pub struct Dropout<T = f64> {
+    rate : f64,
+    size : usize,
+    mask : Option<Matrix<T>>,
+}
+
+impl<T : Float> Dropout<T> {
+    /// Creates a dropout layer for inputs with `size` entries, which must drop them with a
+    /// probability of at least zero and less than one.
+    pub fn new(size : usize, rate : f64) -> Result<Dropout<T>, NetworkError> {
+        if !(0.0..1.0).contains(&rate) {
+            return Err(NetworkError::invalid_config(format!("Attempt to use a dropout rate of {}, which is not at least zero and less than one.", rate)))
+        }
+
+        Ok(Dropout {
+            rate,
+            size,
+            mask : None,
+        })
+    }
+
+    /// Returns the probability of each input being dropped while training.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    fn deserialise(mut entries : Entries) -> Result<Dropout<T>, NetworkError> {
+        let size : usize = parse(&mut entries, "layer size")?;
+        let rate : f64 = parse(&mut entries, "dropout rate")?;
+
+        match Dropout::new(size, rate) {
+            Ok(dropout) => Ok(dropout),
+            Err(_) => Err(entries.error(format!("Saved network has an invalid dropout rate {}.", rate)))
+        }
+    }
+}
+
+impl<T : Float> Layer<T> for Dropout<T> {
+    fn name(&self) -> &str {
+        "dropout"
+    }
+
+    fn inputs(&self) -> usize {
+        self.size
+    }
+
+    fn outputs(&self) -> usize {
+        self.size
+    }
+
+    fn forward(&self, input : &Matrix<T>) -> Matrix<T> {
+        input.clone()
+    }
+
+    fn forward_train(&mut self, input : &Matrix<T>, context : &mut TrainContext) -> Matrix<T> {
+        let mask = Matrix::new(input.rows(), input.cols(), weights_gen::dropout_mask(input.rows() * input.cols(), self.rate, context.rng()));
+        let output = Matrix::hadamard(input, &mask);
+        self.mask = Some(mask);
+        output
+    }
+
+    fn backward(&mut self, output_gradient : &Matrix<T>, propagate : bool, _context : &mut TrainContext) -> Option<Matrix<T>> {
+        let mask = match &self.mask {
+            Some(mask) => mask,
+            None => panic!("Attempt to backpropagate through a layer which has not been fed forward.")
+        };
+
+        if propagate {
+            Some(Matrix::hadamard(output_gradient, mask))
+        }
+        else {
+            None
+        }
+    }
+
+    fn serialise(&self) -> Result<String, NetworkError> {
+        Ok(format!("dropout {} {}\n", self.size, self.rate))
+    }
+
+    fn clone_box(&self) -> Box<dyn Layer<T>> {
+        Box::new(self.clone())
+    }
+}
+
+/// The default weight given to each mini-batch's statistics when updating the running statistics
+/// of batch normalisation.
+pub const DEFAULT_MOMENTUM : f64 = 0.1;
+
+/// The default value added to the variance in batch and layer normalisation, so that inputs which do not
+/// vary are not divided by zero.
+pub const DEFAULT_EPSILON : f64 = 1e-5;
+
+/// A layer which normalises each of its inputs to have a mean of zero and a variance of one across
+/// the batch, and then scales and shifts them by learnt amounts. While training the statistics are
+/// of the whole mini-batch, however it is split between threads, and a running average of them is
+/// kept weighted by the momentum. A mini-batch of a single input has no variance, so it leaves the
+/// running averages unchanged. When testing or used for inference the running averages are used
+/// instead, so that each output only depends on its own input.
+#[derive(Debug, Clone)]
+pub struct BatchNorm<T = f64> {
+    scale : Vector<T>,
+    shift : Vector<T>,
+    running_mean : Vector<T>,
+    running_variance : Vector<T>,
+    momentum : f64,
+    epsilon : f64,
+    scale_gradient : Vector<T>,
+    shift_gradient : Vector<T>,
+    // The normalised inputs, the reciprocals of the standard deviations and the number of inputs in
+    // the whole mini-batch.
+    normalised : Option<(Matrix<T>, Vector<T>, usize)>,
+}
+
+impl<T : Float> BatchNorm<T> {
+    /// Creates a batch normalisation layer for inputs with `size` entries, which starts without
+    /// scaling or shifting them, using the default momentum and epsilon.
+    pub fn new(size : usize) -> BatchNorm<T> {
+        BatchNorm {
+            scale : Vector::new(vec![T::ONE; size]),
+            shift : Vector::zeros(size),
+            running_mean : Vector::zeros(size),
+            running_variance : Vector::new(vec![T::ONE; size]),
+            momentum : DEFAULT_MOMENTUM,
+            epsilon : DEFAULT_EPSILON,
+            scale_gradient : Vector::zeros(size),
+            shift_gradient : Vector::zeros(size),
+            normalised : None,
+        }
+    }
+
+    /// Sets the weight given to each mini-batch's statistics when updating the running statistics,
+    /// which must be greater than zero and at most one.
+    pub fn with_momentum(mut self, momentum : f64) -> Result<BatchNorm<T>, NetworkError> {
+        if momentum.is_nan() || momentum <= 0.0 || momentum > 1.0 {
+            return Err(NetworkError::invalid_config(format!("Attempt to use a batch normalisation momentum of {}, which is not greater than zero and at most one.", momentum)))
+        }
+
+        self.momentum = momentum;
+        Ok(self)
+    }
+
+    /// Sets the value added to the variance before dividing by its square root, which must be
+    /// positive.
+    pub fn with_epsilon(mut self, epsilon : f64) -> Result<BatchNorm<T>, NetworkError> {
+        if epsilon.is_nan() || epsilon <= 0.0 {
+            return Err(NetworkError::invalid_config(format!("Attempt to use a batch normalisation epsilon of {}, which is not positive.", epsilon)))
+        }
+
+        self.epsilon = epsilon;
+        Ok(self)
+    }
+
+    /// Returns the weight given to each mini-batch's statistics when updating the running
+    /// statistics.
+    pub fn momentum(&self) -> f64 {
+        self.momentum
+    }
+
+    /// Returns the value added to the variance before dividing by its square root.
+    pub fn epsilon(&self) -> f64 {
+        self.epsilon
+    }
+
+    fn deserialise(mut entries : Entries, lines : &mut Lines) -> Result<BatchNorm<T>, NetworkError> {
+        let size : usize = parse(&mut entries, "layer size")?;
+        let momentum : f64 = parse(&mut entries, "batch normalisation momentum")?;
+        let epsilon : f64 = parse(&mut entries, "batch normalisation epsilon")?;
+
+        // The values are read before the layer is created, so that its size is only trusted once
+        // the file is known to hold that many values.
+        let scale = parse_all(expect_line(lines, "scale")?, size, "scale")?;
+        let shift = parse_all(expect_line(lines, "shift")?, size, "shift")?;
+        let running_mean = parse_all(expect_line(lines, "mean")?, size, "running mean")?;
+        let running_variance = parse_all(expect_line(lines, "variance")?, size, "running variance")?;
+
+        let mut layer =
+            match BatchNorm::new(size).with_momentum(momentum).and_then(|layer| layer.with_epsilon(epsilon)) {
+                Ok(layer) => layer,
+                Err(_) => return Err(entries.error(format!("Saved network has an invalid batch normalisation momentum {} or epsilon {}.", momentum, epsilon)))
+            };
+
+        layer.scale = Vector::new(scale);
+        layer.shift = Vector::new(shift);
+        layer.running_mean = Vector::new(running_mean);
+        layer.running_variance = Vector::new(running_variance);
+
+        Ok(layer)
+    }
+}
+
+impl<T : Float> Layer<T> for BatchNorm<T> {
+    fn name(&self) -> &str {
+        "batchnorm"
+    }
+
+    fn inputs(&self) -> usize {
+        self.scale.len()
+    }
+
+    fn outputs(&self) -> usize {
+        self.scale.len()
+    }
+
+    fn forward(&self, input : &Matrix<T>) -> Matrix<T> {
+        // With the running statistics fixed, each entry is mapped by y = a * x + b.
+        let epsilon = T::from_f64(self.epsilon);
+        let factors : Vec<T> =
+            self.scale.iter()
+            .zip(self.running_variance.iter())
+            .map(|(scale, variance)| *scale / (*variance + epsilon).sqrt())
+            .collect();
+        let offsets : Vec<T> =
+            self.shift.iter()
+            .zip(self.running_mean.iter())
+            .zip(factors.iter())
+            .map(|((shift, mean), factor)| *shift - *factor * *mean)
+            .collect();
+
+        input.map_rows(|row| {
+            row.iter()
+            .zip(factors.iter().zip(offsets.iter()))
+            .map(|(value, (factor, offset))| *factor * *value + *offset)
+            .collect()
+        })
+    }
+
+    fn forward_train(&mut self, input : &Matrix<T>, context : &mut TrainContext) -> Matrix<T> {
+        // The sums are combined across the shares of the mini-batch, so that every share is
+        // normalised by the statistics of the whole of it.
+        let mut sums : Vec<f64> = input.column_sums().iter().map(|sum| sum.to_f64()).collect();
+        sums.push(input.rows() as f64);
+        let mut sums = context.sum(sums);
+        let count = sums.pop().unwrap();
+
+        let mean = Vector::new(sums.iter().map(|sum| T::from_f64(sum / count)).collect());
+        let centred = input.map_rows(|row| row.iter().zip(mean.iter()).map(|(value, mean)| *value - *mean).collect());
+        let squares = Matrix::hadamard(&centred, &centred).column_sums().iter().map(|sum| sum.to_f64()).collect();
+        let variance = Vector::new(context.sum(squares).iter().map(|sum| T::from_f64(sum / count)).collect());
+
+        let epsilon = T::from_f64(self.epsilon);
+        let inverse_deviations = Vector::new(variance.iter().map(|variance| T::ONE / (*variance + epsilon).sqrt()).collect());
+        let normalised = centred.map_rows(|row| row.iter().zip(inverse_deviations.iter()).map(|(value, inverse)| *value * *inverse).collect());
+
+        // The running variance is of the population, so the batch variance is corrected for bias.
+        if count > 1.0 {
+            let momentum = T::from_f64(self.momentum);
+            let correction = T::from_f64(count / (count - 1.0));
+            for (running, mean) in self.running_mean.0.iter_mut().zip(mean.iter()) {
+                *running = (T::ONE - momentum) * *running + momentum * *mean;
+            }
+            for (running, variance) in self.running_variance.0.iter_mut().zip(variance.iter()) {
+                *running = (T::ONE - momentum) * *running + momentum * correction * *variance;
+            }
+        }
+
+        let output = normalised.map_rows(|row| {
+            row.iter()
+            .zip(self.scale.iter().zip(self.shift.iter()))
+            .map(|(value, (scale, shift))| *scale * *value + *shift)
+            .collect()
+        });
+        self.normalised = Some((normalised, inverse_deviations, count as usize));
+        output
+    }
+
+    fn backward(&mut self, output_gradient : &Matrix<T>, propagate : bool, context : &mut TrainContext) -> Option<Matrix<T>> {
+        let (normalised, inverse_deviations, count) = match &self.normalised {
+            Some(normalised) => normalised,
+            None => panic!("Attempt to backpropagate through a layer which has not been fed forward.")
+        };
+
+        // The gradients of the parameters are of this share of the mini-batch, as the network sums
+        // them across the shares.
+        self.scale_gradient = Matrix::hadamard(output_gradient, normalised).column_sums();
+        self.shift_gradient = output_gradient.column_sums();
+
+        if !propagate {
+            return None
+        }
+
+        // As every output depends on the statistics of the whole batch, the derivative with
+        // respect to each input is dx = a * (n * dy - sum(dy) - x' * sum(dy * x')) / n, where x' is
+        // the normalised input, a is the scale over the standard deviation and the sums are over
+        // the whole mini-batch.
+        let sums : Vec<T> =
+            context.sum(self.scale_gradient.iter().chain(self.shift_gradient.iter()).map(|sum| sum.to_f64()).collect())
+            .into_iter()
+            .map(T::from_f64)
+            .collect();
+        let (scale_sums, shift_sums) = sums.split_at(self.scale.len());
+
+        let count = T::from_f64(*count as f64);
+        let factors : Vec<T> =
+            self.scale.iter()
+            .zip(inverse_deviations.iter())
+            .map(|(scale, inverse)| *scale * *inverse / count)
+            .collect();
+
+        Some(Matrix::zip_map_rows(output_gradient, normalised, |gradient, normalised| {
+            (0..gradient.len())
+            .map(|i| factors[i] * (count * gradient[i] - shift_sums[i] - normalised[i] * scale_sums[i]))
+            .collect()
+        }))
+    }
+
+    fn parameters(&self) -> Vec<&[T]> {
+        vec![&self.scale.0, &self.shift.0]
+    }
+
+    fn parameters_mut(&mut self) -> Vec<&mut [T]> {
+        vec![&mut self.scale.0, &mut self.shift.0]
+    }
+
+    fn gradients(&self) -> Vec<&[T]> {
+        vec![&self.scale_gradient.0, &self.shift_gradient.0]
+    }
+
+    fn state(&self) -> Vec<&[T]> {
+        vec![&self.running_mean.0, &self.running_variance.0]
+    }
+
+    fn state_mut(&mut self) -> Vec<&mut [T]> {
+        vec![&mut self.running_mean.0, &mut self.running_variance.0]
+    }
+
+    fn parameter_kinds(&self) -> Vec<ParameterKind> {
+        vec![ParameterKind::Biases, ParameterKind::Biases]
+    }
+
+    fn serialise(&self) -> Result<String, NetworkError> {
+        Ok(format!(
+            "batchnorm {} {} {}\nscale {}\nshift {}\nmean {}\nvariance {}\n",
+            self.inputs(),
+            self.momentum,
+            self.epsilon,
+            join(&self.scale.0),
+            join(&self.shift.0),
+            join(&self.running_mean.0),
+            join(&self.running_variance.0)
+        ))
+    }
+
+    fn clone_box(&self) -> Box<dyn Layer<T>> {
+        Box::new(self.clone())
+    }
+}
+
+/// A layer which normalises the entries of each input to have a mean of zero and a variance of
+/// one, and then multiplies them by a learnt gain and adds a learnt bias. Unlike batch
+/// normalisation each input is normalised by its own statistics, so it behaves the same whatever
+/// the size of the batch and whether training or not.
+#[derive(Debug, Clone)]
+pub struct LayerNorm<T = f64> {
+    gain : Vector<T>,
+    bias : Vector<T>,
+    epsilon : f64,
+    gain_gradient : Vector<T>,
+    bias_gradient : Vector<T>,
+    normalised : Option<(Matrix<T>, Vector<T>)>,
+}
+
+impl<T : Float> LayerNorm<T> {
+    /// Creates a layer normalisation layer for inputs with `size` entries, with a gain of one and
+    /// a bias of zero, using the default epsilon.
+    pub fn new(size : usize) -> LayerNorm<T> {
+        LayerNorm {
+            gain : Vector::new(vec![T::ONE; size]),
+            bias : Vector::zeros(size),
+            epsilon : DEFAULT_EPSILON,
+            gain_gradient : Vector::zeros(size),
+            bias_gradient : Vector::zeros(size),
+            normalised : None,
+        }
+    }
+
+    /// Sets the value added to the variance before dividing by its square root, which must be
+    /// positive.
+    pub fn with_epsilon(mut self, epsilon : f64) -> Result<LayerNorm<T>, NetworkError> {
+        if epsilon.is_nan() || epsilon <= 0.0 {
+            return Err(NetworkError::invalid_config(format!("Attempt to use a layer normalisation epsilon of {}, which is not positive.", epsilon)))
+        }
+
+        self.epsilon = epsilon;
+        Ok(self)
+    }
+
+    /// Returns the value added to the variance before dividing by its square root.
+    pub fn epsilon(&self) -> f64 {
+        self.epsilon
+    }
+
+    /// Normalises each row of the input, returning the normalised rows along with the reciprocal
+    /// of the standard deviation of each.
+    fn normalise(&self, input : &Matrix<T>) -> (Matrix<T>, Vector<T>) {
+        let size = T::from_f64(input.cols() as f64);
+        let epsilon = T::from_f64(self.epsilon);
+
+        let mut inverse_deviations = Vec::with_capacity(input.rows());
+        let normalised = input.map_rows(|row| {
+            let mean = row.iter().copied().sum::<T>() / size;
+            let variance = row.iter().map(|value| (*value - mean).powi(2)).sum::<T>() / size;
+            let inverse = T::ONE / (variance + epsilon).sqrt();
+            inverse_deviations.push(inverse);
+
+            row.iter().map(|value| (*value - mean) * inverse).collect()
+        });
+
+        (normalised, Vector::new(inverse_deviations))
+    }
+
+    /// Applies the gain and bias to the normalised rows.
+    fn transform(&self, normalised : &Matrix<T>) -> Matrix<T> {
+        normalised.map_rows(|row| {
+            row.iter()
+            .zip(self.gain.iter().zip(self.bias.iter()))
+            .map(|(value, (gain, bias))| *gain * *value + *bias)
+            .collect()
+        })
+    }
+
+    fn deserialise(mut entries : Entries, lines : &mut Lines) -> Result<LayerNorm<T>, NetworkError> {
+        let size : usize = parse(&mut entries, "layer size")?;
+        let epsilon : f64 = parse(&mut entries, "layer normalisation epsilon")?;
+
+        // As for batch normalisation, the values are read before the layer is created.
+        let gain = parse_all(expect_line(lines, "gain")?, size, "gain")?;
+        let bias = parse_all(expect_line(lines, "bias")?, size, "bias")?;
+
+        let mut layer =
+            match LayerNorm::new(size).with_epsilon(epsilon) {
+                Ok(layer) => layer,
+                Err(_) => return Err(entries.error(format!("Saved network has an invalid layer normalisation epsilon {}.", epsilon)))
+            };
+
+        layer.gain = Vector::new(gain);
+        layer.bias = Vector::new(bias);
+
+        Ok(layer)
+    }
+}
+
+impl<T : Float> Layer<T> for LayerNorm<T> {
+    fn name(&self) -> &str {
+        "layernorm"
+    }
+
+    fn inputs(&self) -> usize {
+        self.gain.len()
+    }
+
+    fn outputs(&self) -> usize {
+        self.gain.len()
+    }
+
+    fn forward(&self, input : &Matrix<T>) -> Matrix<T> {
+        self.transform(&self.normalise(input).0)
+    }
+
+    fn forward_train(&mut self, input : &Matrix<T>, _context : &mut TrainContext) -> Matrix<T> {
+        let (normalised, inverse_deviations) = self.normalise(input);
+        let output = self.transform(&normalised);
+        self.normalised = Some((normalised, inverse_deviations));
+        output
+    }
+
+    fn backward(&mut self, output_gradient : &Matrix<T>, propagate : bool, _context : &mut TrainContext) -> Option<Matrix<T>> {
+        let (normalised, inverse_deviations) = match &self.normalised {
+            Some(normalised) => normalised,
+            None => panic!("Attempt to backpropagate through a layer which has not been fed forward.")
+        };
+
+        self.gain_gradient = Matrix::hadamard(output_gradient, normalised).column_sums();
+        self.bias_gradient = output_gradient.column_sums();
+
+        if !propagate {
+            return None
+        }
+
+        // Within each input, with g = gain * dy and x' the normalised entries, the derivative with
+        // respect to each entry is dx = (n * g - sum(g) - x' * sum(g * x')) / (n * deviation).
+        let size = T::from_f64(output_gradient.cols() as f64);
+        let mut row_no = 0;
+        Some(Matrix::zip_map_rows(output_gradient, normalised, |gradient, normalised| {
+            let scaled : Vec<T> = gradient.iter().zip(self.gain.iter()).map(|(gradient, gain)| *gradient * *gain).collect();
+            let sum = scaled.iter().copied().sum::<T>();
+            let weighted_sum = T::dot(&scaled, normalised);
+            let factor = inverse_deviations.0[row_no] / size;
+            row_no += 1;
+
+            scaled
+            .iter()
+            .zip(normalised.iter())
+            .map(|(scaled, normalised)| factor * (size * *scaled - sum - *normalised * weighted_sum))
+            .collect()
+        }))
+    }
+
+    fn parameters(&self) -> Vec<&[T]> {
+        vec![&self.gain.0, &self.bias.0]
+    }
+
+    fn parameters_mut(&mut self) -> Vec<&mut [T]> {
+        vec![&mut self.gain.0, &mut self.bias.0]
+    }
+
+    fn gradients(&self) -> Vec<&[T]> {
+        vec![&self.gain_gradient.0, &self.bias_gradient.0]
+    }
+
+    fn parameter_kinds(&self) -> Vec<ParameterKind> {
+        vec![ParameterKind::Biases, ParameterKind::Biases]
+    }
+
+    fn serialise(&self) -> Result<String, NetworkError> {
+        Ok(format!(
+            "layernorm {} {}\ngain {}\nbias {}\n",
+            self.inputs(),
+            self.epsilon,
+            join(&self.gain.0),
+            join(&self.bias.0)
+        ))
+    }
+
+    fn clone_box(&self) -> Box<dyn Layer<T>> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Layer, BatchNorm, LayerNorm, TrainContext};
+    use crate::algebra::Matrix;
+    use crate::weights_gen;
+
+    const ROWS : usize = 5;
+    const COLS : usize = 4;
+
+    /// The step used to estimate derivatives by central differences, and how far the estimates may
+    /// be from the derivatives found by backpropagation.
+    const STEP : f64 = 1e-6;
+    const TOLERANCE : f64 = 1e-6;
+
+    fn matrix(offset : f64) -> Matrix<f64> {
+        let mut matrix = Matrix::zeros(ROWS, COLS);
+        for (i, value) in matrix.values_mut().iter_mut().enumerate() {
+            *value = 1.5 * ((i as f64 + offset) * 0.9).sin() + 0.1 * i as f64;
+        }
+        matrix
+    }
+
+    /// The cost of the outputs, which is a weighted sum so that its derivative with respect to
+    /// each output is the weight.
+    fn cost(layer : &mut dyn Layer<f64>, input : &Matrix<f64>, weights : &Matrix<f64>) -> f64 {
+        let mut rng = weights_gen::seeded(0);
+        let output = layer.forward_train(input, &mut TrainContext::new(&mut rng));
+        output.values().iter().zip(weights.values()).map(|(output, weight)| output * weight).sum()
+    }
+
+    fn assert_close(backpropagated : f64, estimated : f64) {
+        assert!((backpropagated - estimated).abs() <= TOLERANCE * (1.0 + estimated.abs()), "backpropagated {} but estimated {}", backpropagated, estimated);
+    }
+
+    /// Compares the gradients backpropagated through a normalisation layer, with respect to both
+    /// its inputs and its parameters, with estimates by central differences of the cost.
+    fn check_gradients<L : Layer<f64> + Clone>(mut layer : L) {
+        // The scale and shift are moved away from their initial values, so that they are tested.
+        for (set, parameters) in layer.parameters_mut().into_iter().enumerate() {
+            for (i, value) in parameters.iter_mut().enumerate() {
+                *value = 0.5 + 0.3 * i as f64 - 0.2 * set as f64;
+            }
+        }
+        let (input, weights) = (matrix(0.0), matrix(2.0));
+
+        let mut rng = weights_gen::seeded(0);
+        let mut context = TrainContext::new(&mut rng);
+        let mut backpropagated = layer.clone();
+        backpropagated.forward_train(&input, &mut context);
+        let input_gradient = backpropagated.backward(&weights, true, &mut context).unwrap();
+
+        for i in 0..input.values().len() {
+            let mut estimate = 0.0;
+            for (sign, step) in [(1.0, STEP), (-1.0, -STEP)] {
+                let mut shifted = input.clone();
+                shifted.values_mut()[i] += step;
+                estimate += sign * cost(&mut layer.clone(), &shifted, &weights);
+            }
+            assert_close(input_gradient.values()[i], estimate / (2.0 * STEP));
+        }
+
+        let gradients : Vec<Vec<f64>> = backpropagated.gradients().into_iter().map(|gradient| gradient.to_vec()).collect();
+        for (set, gradient) in gradients.iter().enumerate() {
+            for (i, value) in gradient.iter().enumerate() {
+                let mut estimate = 0.0;
+                for (sign, step) in [(1.0, STEP), (-1.0, -STEP)] {
+                    let mut shifted = layer.clone();
+                    shifted.parameters_mut()[set][i] += step;
+                    estimate += sign * cost(&mut shifted, &input, &weights);
+                }
+                assert_close(*value, estimate / (2.0 * STEP));
+            }
+        }
+    }
+
+    #[test]
+    fn batch_norm_gradients_match_differences() {
+        check_gradients(BatchNorm::new(COLS));
+    }
+
+    #[test]
+    fn layer_norm_gradients_match_differences() {
+        check_gradients(LayerNorm::new(COLS));
+    }
+}
@@ -1,21 +1,45 @@
-mod algebra;
+pub mod algebra;
+// With the `blas` feature the dot products are left to BLAS.
+#[cfg_attr(feature = "blas", allow(dead_code, unused_imports))]
+mod kernels;
+#[cfg(feature = "blas")]
+mod blas;
 mod unsafe_vec;
+pub mod error;
+pub mod float;
 pub mod data;
 pub mod weights_gen;
 pub mod activation;
+pub mod layer;
 pub mod network;
+pub mod builder;
+pub mod optimizer;
+pub mod schedule;
+pub mod regularisation;
+pub mod loss;
+pub mod save;
+pub mod checkpoint;
+
+/// The scalar and SIMD kernels, for the benchmarks in `benches` to compare. This is not part of the
+/// public API.
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::kernels::scalar;
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    pub use crate::kernels::simd;
+}
 
 
-use crate::algebra::{Vector, Matrix};
+use crate::algebra::Vector;
 
 #[derive(Clone)]
-pub struct DataSet(Vec<Vector>);
+pub struct DataSet<T = f64>(Vec<Vector<T>>);
 
 #[derive(Debug, Clone)]
-pub struct Network {
-    structure : Vec<usize>,
-    weights : Vec<Matrix>,
-    biases : Vec<Vector>, 
-    activ : fn(f64) -> f64,
-    activ_diff : fn(f64) -> f64
+pub struct Network<T : float::Float = f64> {
+    layers : Vec<Box<dyn layer::Layer<T>>>,
+    mode : network::Mode,
+    regularisation : regularisation::Regularisation,
+    threads : usize,
+    rng : weights_gen::ChaCha8Rng
 }
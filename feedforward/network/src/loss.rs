@@ -0,0 +1,208 @@
+use crate::activation;
+use crate::error::NetworkError;
+use crate::float::Float;
+
+/// Smallest probability used when taking logarithms, so that confidently wrong outputs give a large
+/// but finite cost.
+const EPSILON : f64 = 1e-12;
+
+/// Returns `EPSILON` in the precision of the network, raised to the machine epsilon if that is
+/// larger, so that one minus it is still less than one.
+fn epsilon<T : Float>() -> T {
+    T::from_f64(EPSILON).max(T::EPSILON)
+}
+
+/// A function measuring how far the output of a network is from the expected output, which is
+/// minimised during training. Losses are shared between the threads used for training, and are
+/// implemented for each precision of network.
+pub trait Loss<T : Float = f64> : Sync {
+    /// Calculates the cost for a single output of the network.
+    fn cost(&self, output : &[T], expected : &[T]) -> T;
+
+    /// Calculates the derivative of the cost with respect to each output of the network.
+    fn gradient(&self, output : &[T], expected : &[T]) -> Vec<T>;
+
+    /// Calculates the derivative of the cost with respect to the inputs of a softmax output layer,
+    /// given the probabilities it output. By default this applies the full Jacobian of softmax to
+    /// the gradient of the loss, but losses can provide a simpler fused form.
+    fn softmax_gradient(&self, probabilities : &[T], expected : &[T]) -> Vec<T> {
+        activation::softmax_backward(probabilities, &self.gradient(probabilities, expected))
+    }
+}
+
+/// The mean of the squared differences between the output and expected output.
+#[derive(Debug, Clone, Copy)]
+pub struct MeanSquaredError;
+
+impl<T : Float> Loss<T> for MeanSquaredError {
+    fn cost(&self, output : &[T], expected : &[T]) -> T {
+        output
+        .iter()
+        .zip(expected.iter())
+        .map(|(a, b)| (*a - *b).powi(2))
+        .sum::<T>() / T::from_f64(output.len() as f64)
+    }
+
+    fn gradient(&self, output : &[T], expected : &[T]) -> Vec<T> {
+        let n = T::from_f64(output.len() as f64);
+        let two = T::from_f64(2.0);
+
+        output
+        .iter()
+        .zip(expected.iter())
+        .map(|(a, b)| two * (*a - *b) / n)
+        .collect()
+    }
+}
+
+/// The mean of the absolute differences between the output and expected output.
+#[derive(Debug, Clone, Copy)]
+pub struct MeanAbsoluteError;
+
+impl<T : Float> Loss<T> for MeanAbsoluteError {
+    fn cost(&self, output : &[T], expected : &[T]) -> T {
+        output
+        .iter()
+        .zip(expected.iter())
+        .map(|(a, b)| (*a - *b).abs())
+        .sum::<T>() / T::from_f64(output.len() as f64)
+    }
+
+    fn gradient(&self, output : &[T], expected : &[T]) -> Vec<T> {
+        let n = T::from_f64(output.len() as f64);
+
+        output
+        .iter()
+        .zip(expected.iter())
+        .map(|(a, b)| if a == b { T::ZERO } else { (*a - *b).signum() / n })
+        .collect()
+    }
+}
+
+/// Squared error for differences smaller than `delta` and absolute error beyond it, which makes
+/// training less sensitive to outliers than squared error.
+#[derive(Debug, Clone, Copy)]
+pub struct Huber {
+    delta : f64,
+}
+
+impl Huber {
+    /// Creates a Huber loss switching between squared and absolute error at `delta`, which must be
+    /// positive.
+    pub fn new(delta : f64) -> Result<Huber, NetworkError> {
+        if delta.is_nan() || delta <= 0.0 {
+            return Err(NetworkError::invalid_config(format!("Attempt to use a Huber loss with a delta of {}, which is not positive.", delta)))
+        }
+
+        Ok(Huber { delta })
+    }
+
+    /// Returns the difference at which the loss switches from squared to absolute error.
+    pub fn delta(&self) -> f64 {
+        self.delta
+    }
+}
+
+impl<T : Float> Loss<T> for Huber {
+    fn cost(&self, output : &[T], expected : &[T]) -> T {
+        let (delta, half) = (T::from_f64(self.delta), T::from_f64(0.5));
+
+        output
+        .iter()
+        .zip(expected.iter())
+        .map(|(a, b)| {
+            let diff = (*a - *b).abs();
+            if diff <= delta {
+                half * diff * diff
+            }
+            else {
+                delta * (diff - half * delta)
+            }
+        })
+        .sum::<T>() / T::from_f64(output.len() as f64)
+    }
+
+    fn gradient(&self, output : &[T], expected : &[T]) -> Vec<T> {
+        let n = T::from_f64(output.len() as f64);
+        let delta = T::from_f64(self.delta);
+
+        output
+        .iter()
+        .zip(expected.iter())
+        .map(|(a, b)| (*a - *b).clamp(-delta, delta) / n)
+        .collect()
+    }
+}
+
+/// Cross-entropy for outputs which are each an independent probability, such as those from a
+/// sigmoid output layer.
+#[derive(Debug, Clone, Copy)]
+pub struct BinaryCrossEntropy;
+
+impl<T : Float> Loss<T> for BinaryCrossEntropy {
+    fn cost(&self, output : &[T], expected : &[T]) -> T {
+        let epsilon = epsilon::<T>();
+
+        output
+        .iter()
+        .zip(expected.iter())
+        .map(|(a, b)| {
+            let a = a.clamp(epsilon, T::ONE - epsilon);
+            -(*b * a.ln() + (T::ONE - *b) * (T::ONE - a).ln())
+        })
+        .sum::<T>() / T::from_f64(output.len() as f64)
+    }
+
+    fn gradient(&self, output : &[T], expected : &[T]) -> Vec<T> {
+        let n = T::from_f64(output.len() as f64);
+        let epsilon = epsilon::<T>();
+
+        output
+        .iter()
+        .zip(expected.iter())
+        .map(|(a, b)| {
+            let a = a.clamp(epsilon, T::ONE - epsilon);
+            (a - *b) / (a * (T::ONE - a) * n)
+        })
+        .collect()
+    }
+}
+
+/// Cross-entropy for outputs which together form a probability distribution over classes, with the
+/// expected output being a one-hot (or otherwise normalised) distribution.
+#[derive(Debug, Clone, Copy)]
+pub struct CategoricalCrossEntropy;
+
+impl<T : Float> Loss<T> for CategoricalCrossEntropy {
+    fn cost(&self, output : &[T], expected : &[T]) -> T {
+        let epsilon = epsilon::<T>();
+
+        output
+        .iter()
+        .zip(expected.iter())
+        .map(|(a, b)| -*b * a.max(epsilon).ln())
+        .sum()
+    }
+
+    fn gradient(&self, output : &[T], expected : &[T]) -> Vec<T> {
+        let epsilon = epsilon::<T>();
+
+        output
+        .iter()
+        .zip(expected.iter())
+        .map(|(a, b)| -*b / a.max(epsilon))
+        .collect()
+    }
+
+    /// Combined with softmax the derivative reduces to `p - y` (scaled by the total of the expected
+    /// output, which is one for a distribution), avoiding the division by small probabilities.
+    fn softmax_gradient(&self, probabilities : &[T], expected : &[T]) -> Vec<T> {
+        let total : T = expected.iter().cloned().sum();
+
+        probabilities
+        .iter()
+        .zip(expected.iter())
+        .map(|(p, y)| *p * total - *y)
+        .collect()
+    }
+}
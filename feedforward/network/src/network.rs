@@ -1,223 +1,716 @@
-use std::collections::VecDeque;
-
-use crate::algebra::{Vector, Matrix};
 use std::vec;
+use std::thread;
+use std::sync::{Condvar, Mutex, PoisonError, mpsc};
+
+use crate::algebra::Matrix;
+use crate::{DataSet, Network, weights_gen};
+use crate::weights_gen::{ChaCha8Rng, Initialiser};
+use crate::activation::Activation;
+use crate::error::NetworkError;
+use crate::float::Float;
+use crate::layer::{Layer, Dense, ActivationLayer, ParameterKind, TrainContext};
+use crate::optimizer::Optimizer;
+use crate::regularisation::Regularisation;
+use crate::schedule::Scheduler;
+use crate::loss::Loss;
+
+/// The number of inputs fed forward together when testing a data set.
+const TEST_BATCH_SIZE : usize = 256;
+
+/// A copy of the layers which backpropagates a share of each mini-batch while training.
+type Replica<T> = Mutex<vec::Vec<Box<dyn Layer<T>>>>;
+
+/// The threads training a network over an epoch, each of which backpropagates its share of every
+/// mini-batch through its own replica of the layers. The first share is backpropagated on the
+/// current thread. The replicas are kept for the whole epoch, and only the parameters and state of
+/// the network are copied into them after each update.
+struct Workers<'a, T : Float> {
+    replicas : &'a [Replica<T>],
+    reduction : &'a Reduction,
+    jobs : vec::Vec<mpsc::Sender<(&'a [usize], ChaCha8Rng)>>,
+    done : vec::Vec<mpsc::Receiver<()>>,
+    gradients : vec::Vec<vec::Vec<T>>,
+}
+
+/// Sums values across the shares of a mini-batch for `TrainContext::sum`. Each share adds its
+/// values and waits until every share has, and the totals are summed in the order of the shares so
+/// that they do not depend on which thread is fastest.
+pub (crate) struct Reduction {
+    round : Mutex<Round>,
+    completed : Condvar,
+}
+
+/// The progress of the current round of a `Reduction`.
+struct Round {
+    shares : usize,
+    values : vec::Vec<Option<vec::Vec<f64>>>,
+    arrived : usize,
+    number : usize,
+    totals : vec::Vec<f64>,
+    poisoned : bool,
+}
 
-use crate::{DataSet, Network};
+impl Reduction {
+    fn new() -> Reduction {
+        Reduction {
+            round : Mutex::new(Round { shares : 1, values : vec::Vec::new(), arrived : 0, number : 0, totals : vec::Vec::new(), poisoned : false }),
+            completed : Condvar::new(),
+        }
+    }
+
+    /// Sets the number of shares of the next mini-batch.
+    fn start(&self, shares : usize) {
+        let mut round = self.round.lock().unwrap();
+        round.shares = shares;
+        round.values = vec![None; shares];
+    }
 
-use std::vec::Vec as AlgVec;
-//use crate::unsafe_vec::UnsafeVec as AlgVec;
+    /// Adds the values of a share to the current round, and returns the totals once every share
+    /// has added theirs.
+    pub (crate) fn sum(&self, share : usize, values : vec::Vec<f64>) -> vec::Vec<f64> {
+        let mut round = self.round.lock().unwrap();
+        if round.shares == 1 {
+            return values
+        }
+
+        round.values[share] = Some(values);
+        round.arrived += 1;
+        if round.arrived == round.shares {
+            let mut shares = round.values.iter_mut().map(|values| values.take().unwrap());
+            let mut totals = shares.next().unwrap();
+            for values in shares {
+                for (total, value) in totals.iter_mut().zip(values) {
+                    *total += value;
+                }
+            }
+
+            round.totals = totals;
+            round.arrived = 0;
+            round.number += 1;
+            self.completed.notify_all();
+        }
+        else {
+            let number = round.number;
+            while round.number == number && !round.poisoned {
+                round = self.completed.wait(round).unwrap();
+            }
+            if round.poisoned {
+                drop(round);
+                panic!("Attempt to sum values across a mini-batch with a share whose thread panicked.")
+            }
+        }
 
-#[allow(dead_code)]
-#[derive(Debug)]
-struct FeedForwardResult {
-    after_weights : Vector,
-    after_biases : Vector,
-    pub after_activ : Vector,
+        round.totals.clone()
+    }
+
+    /// Wakes any shares waiting for the others, as one of them has panicked and will never arrive.
+    fn poison(&self) {
+        self.round.lock().unwrap_or_else(PoisonError::into_inner).poisoned = true;
+        self.completed.notify_all();
+    }
+}
+
+/// Poisons a reduction if the thread holding it panics.
+struct PoisonOnPanic<'a>(&'a Reduction);
+
+impl Drop for PoisonOnPanic<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.poison();
+        }
+    }
+}
+
+/// Whether a network is being trained or used for inference. Layers such as dropout only behave
+/// differently while training, and testing always feeds forward as for inference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Training,
+    Inference,
 }
 
-impl Network {
-    /// Creates a new feed forward neural network.
+impl<T : Float> Network<T> {
+    /// Creates a new feed forward neural network of fully connected layers, using the same
+    /// activation function and initialisers for every layer. The network takes ownership of the
+    /// random number generator, which is used to initialise the weights and biases and then to
+    /// shuffle the data during training, so a seeded generator reproduces the whole of a training
+    /// run. The structure must have an input layer and at least one more layer, none of which are
+    /// empty. Networks whose layers differ are more easily created with a `NetworkBuilder`.
     pub fn new(
         structure : vec::Vec<usize>,
-        weights_init : fn(usize, usize) -> f64,
-        biases_init : fn(usize) -> f64, 
-        activ : fn(f64) -> f64,
-        activ_diff : fn(f64) -> f64)
-            -> Network {
-
-        // Initialising all the biases.
-        let mut biases = vec::Vec::with_capacity(structure.len() - 1);
-        for layer_size in structure.iter().skip(1) {
-            let mut layer = AlgVec::with_capacity(*layer_size);
-            for _neuron_no in 0..*layer_size {
-                layer.push((biases_init)(*layer_size));
-            }
-            biases.push(Vector::new(layer));
+        weights_init : Initialiser,
+        biases_init : Initialiser,
+        activ : Activation,
+        mut rng : ChaCha8Rng)
+            -> Result<Network<T>, NetworkError> {
+
+        let mut layers : vec::Vec<Box<dyn Layer<T>>> = vec::Vec::with_capacity(2 * structure.len());
+        for sizes in structure.windows(2) {
+            layers.push(Box::new(Dense::new(sizes[0], sizes[1], weights_init, biases_init, &mut rng)?));
+            layers.push(Box::new(ActivationLayer::new(sizes[1], activ.clone())));
         }
 
-        // Initialising all the weights.
-        let mut weights : vec::Vec<Matrix> = vec::Vec::with_capacity(structure.len() - 1);
-        for layer_no in 0..(structure.len() - 1) {
-            let mut weights_set = AlgVec::with_capacity(structure[layer_no + 1] * structure[layer_no]);
-            for _weight_no in 0..(structure[layer_no + 1] * structure[layer_no]) {
-                weights_set.push((weights_init)(structure[layer_no], structure[layer_no + 1]));
+        Network::from_layers(layers, rng)
+    }
+
+    /// Creates a new feed forward neural network from a sequence of layers, checking that each
+    /// layer takes as many inputs as the one before it outputs. Only the last layer can apply
+    /// softmax.
+    pub fn from_layers(layers : vec::Vec<Box<dyn Layer<T>>>, rng : ChaCha8Rng) -> Result<Network<T>, NetworkError> {
+        if layers.is_empty() {
+            return Err(NetworkError::invalid_config("Attempt to create a neural network without any layers."))
+        }
+
+        for (layer_no, layer) in layers.iter().enumerate() {
+            if layer.inputs() == 0 || layer.outputs() == 0 {
+                return Err(NetworkError::invalid_config("Attempt to create a neural network with a layer of zero neurons."))
+            }
+            if layer_no > 0 && layers[layer_no - 1].outputs() != layer.inputs() {
+                return Err(NetworkError::shape_mismatch(format!("the inputs of layer {} ({})", layer_no + 1, layer.name()), layers[layer_no - 1].outputs(), layer.inputs()))
+            }
+            if layer.activation() == Some(Activation::Softmax) && layer_no != layers.len() - 1 {
+                return Err(NetworkError::invalid_config("Attempt to use softmax as the activation function of a hidden layer."))
             }
-            weights.push(
-                Matrix::new(
-                    structure[layer_no + 1],
-                    structure[layer_no],
-                    weights_set
-                )
-            );
         }
 
-        Network {
-            structure,
-            weights,
-            biases,
-            activ,
-            activ_diff
+        Ok(Network {
+            layers,
+            mode : Mode::Training,
+            regularisation : Regularisation::new(),
+            threads : 1,
+            rng
+        })
+    }
+
+    /// Sets the number of threads used to train and test the network. Training splits each
+    /// mini-batch between the threads, and is deterministic for a given seed and number of
+    /// threads. The number of threads is recorded in checkpoints, but not by `save`.
+    pub fn with_threads(mut self, threads : usize) -> Result<Network<T>, NetworkError> {
+        if threads == 0 {
+            return Err(NetworkError::invalid_config("Attempt to use a neural network with zero threads."))
         }
+
+        self.threads = threads;
+        Ok(self)
     }
-}
 
-impl Network {
-    
-    /// Returns the number of layers that the network has.
-    fn num_layers(&self) -> usize {
-        self.structure.len()
+    /// Returns the number of threads used to train and test the network.
+    pub fn threads(&self) -> usize {
+        self.threads
     }
 
-    /// Output is in the form of (input, each layer result)
-    fn feed_forward(&self, input : &Vector) -> (Vector, vec::Vec<FeedForwardResult>) {
-        let mut result : vec::Vec<FeedForwardResult> = vec::Vec::with_capacity(self.num_layers());
+    /// Sets the L1 and L2 penalties and max-norm constraint applied while training, which are also
+    /// included in the cost. Networks are created without any. They are recorded in checkpoints,
+    /// but not by `save`.
+    pub fn with_regularisation(mut self, regularisation : Regularisation) -> Result<Network<T>, NetworkError> {
+        regularisation.validate()?;
 
-        for output_layer_no in 1..self.num_layers() {
+        self.regularisation = regularisation;
+        Ok(self)
+    }
 
-            let after_weights = if output_layer_no == 1 {
-                &self.weights[output_layer_no - 1] * input
-            } else {
-                &self.weights[output_layer_no - 1] * &result.last().unwrap().after_activ
-            };
+    /// Returns the regularisation applied while training.
+    pub fn regularisation(&self) -> Regularisation {
+        self.regularisation
+    }
 
-            let after_biases = &after_weights + &self.biases[output_layer_no - 1];
+    /// Sets whether the network is being trained or used for inference. Networks are created in
+    /// training mode, and cannot be trained in inference mode.
+    pub fn set_mode(&mut self, mode : Mode) {
+        self.mode = mode;
+    }
 
-            let after_activ = after_biases.map(self.activ);
+    /// Returns whether the network is being trained or used for inference.
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Replaces the random number generator used during training, such as after loading a saved
+    /// network.
+    pub fn with_rng(mut self, rng : ChaCha8Rng) -> Network<T> {
+        self.rng = rng;
+        self
+    }
 
-            result.push(FeedForwardResult { after_weights, after_biases, after_activ });
+    /// Replaces the activation function of the output layer, which must be an activation layer. A
+    /// softmax output layer should be trained with categorical cross-entropy.
+    pub fn with_output_activation(mut self, activ : Activation) -> Result<Network<T>, NetworkError> {
+        let output_layer = self.layers.len() - 1;
+        if self.layers[output_layer].activation().is_none() {
+            return Err(NetworkError::invalid_config("Attempt to set the output activation function of a neural network whose last layer is not an activation layer."))
         }
 
-        (input.clone(), result)
+        self.layers[output_layer] = Box::new(ActivationLayer::new(self.outputs(), activ));
+        Ok(self)
     }
 
-    /// Feeds forward the provided data set.
-    pub fn test(&self, input : &DataSet) -> DataSet {
-        let mut result = vec::Vec::with_capacity(input.quantity());
+    /// Returns the layers of the network, in the order inputs are fed through them.
+    pub fn layers(&self) -> &[Box<dyn Layer<T>>] {
+        &self.layers
+    }
+
+    /// Returns the activation function of each activation layer.
+    pub fn activations(&self) -> vec::Vec<Activation> {
+        self.layers
+        .iter()
+        .filter_map(|layer| layer.activation())
+        .collect()
+    }
 
-        for i in 0..input.quantity() {
-            result.push(
-                self.feed_forward(input.internal_get(i)).1.last().unwrap().after_activ.clone()
-            );
+    /// Returns the number of entries in each input to the network.
+    pub fn inputs(&self) -> usize {
+        self.layers[0].inputs()
+    }
+
+    /// Returns the number of entries in each output of the network.
+    pub fn outputs(&self) -> usize {
+        self.layers[self.layers.len() - 1].outputs()
+    }
+}
+
+impl<T : Float> Network<T> {
+
+    /// Feeds forward a batch of inputs, stacked as the rows of a matrix, through each layer in
+    /// turn.
+    fn feed_forward_batch(&self, input : Matrix<T>) -> Matrix<T> {
+        self.layers
+        .iter()
+        .enumerate()
+        .fold(input, |activations, (layer_no, layer)| {
+            let output = layer.forward(&activations);
+            Network::check_shape(layer_no, layer.as_ref(), "outputs", activations.rows(), layer.outputs(), &output);
+            output
+        })
+    }
+
+    /// Panics unless a layer returned a matrix with a row for each input in the batch and the
+    /// number of columns it declares, as layers defined outside this crate are trusted to.
+    fn check_shape(layer_no : usize, layer : &dyn Layer<T>, description : &str, rows : usize, cols : usize, result : &Matrix<T>) {
+        assert!(
+            result.rows() == rows && result.cols() == cols,
+            "Layer {} ({}) returned {} of size {}x{} instead of {}x{}.",
+            layer_no + 1, layer.name(), description, result.rows(), result.cols(), rows, cols
+        );
+    }
+
+    /// Feeds forward the provided data set, in batches, as for inference whatever the mode of the
+    /// network, so dropout layers keep every unit.
+    pub fn test(&self, input : &DataSet<T>) -> Result<DataSet<T>, NetworkError> {
+        self.check_entries("the inputs", self.inputs(), input)?;
+
+        let batch_starts : vec::Vec<usize> = (0..input.quantity()).step_by(TEST_BATCH_SIZE).collect();
+
+        // Each thread is given a contiguous run of batches, and their outputs are joined in order.
+        let outputs = self.parallel_map(&batch_starts, |_, thread_batch_starts| {
+            let mut result = vec::Vec::new();
+
+            for batch_start in thread_batch_starts {
+                let batch_end = usize::min(batch_start + TEST_BATCH_SIZE, input.quantity());
+                let batch_result = self.feed_forward_batch(input.internal_batch(*batch_start..batch_end));
+
+                result.extend(batch_result.to_rows());
+            }
+
+            result
+        });
+
+        Ok(DataSet(outputs.into_iter().flatten().collect()))
+    }
+
+    /// Checks that each set in the data set has the number of entries required by a layer. Empty
+    /// data sets have no sets to check.
+    fn check_entries(&self, description : &str, expected : usize, data : &DataSet<T>) -> Result<(), NetworkError> {
+        if data.quantity() != 0 && data.entries_per_set() != expected {
+            return Err(NetworkError::shape_mismatch(format!("the number of entries in each of {}", description), expected, data.entries_per_set()))
         }
 
-        DataSet(result)
+        Ok(())
     }
-    
-    /// Calculates the cost for the network for a given input.
-    pub fn cost(&self, output : &DataSet, expected : &DataSet) -> vec::Vec<f64> {
 
-        if output.quantity() != expected.quantity() {
-            panic!("Attempt to calculate cost for a neural network with a different number of output data sets as expected output data sets.")
+    /// Checks that two data sets which are used together have the same number of sets.
+    fn check_quantities(description : &str, expected : &DataSet<T>, actual : &DataSet<T>) -> Result<(), NetworkError> {
+        if expected.quantity() != actual.quantity() {
+            return Err(NetworkError::shape_mismatch(format!("the number of {}", description), expected.quantity(), actual.quantity()))
         }
-        if self.structure.last().unwrap() != &output.entries_per_set() || self.structure.last().unwrap() != &expected.entries_per_set() {
-            panic!("Attempt to calculate cost for a neural network with an output or expected output which had data sets with length not matching the number of output neurons in the network.")
+
+        Ok(())
+    }
+
+    /// Splits the items into a contiguous chunk for each of the network's threads, runs the
+    /// function on each chunk in parallel and returns the results in the order of the chunks. The
+    /// function is also given the position of its chunk. If there is only one chunk it is run on
+    /// the current thread.
+    fn parallel_map<I, R, F>(&self, items : &[I], function : F) -> vec::Vec<R>
+        where I : Sync, R : Send, F : Fn(usize, &[I]) -> R + Sync {
+
+        let chunk_size = self.chunk_size(items.len());
+
+        if items.len() <= chunk_size {
+            return vec![function(0, items)]
         }
 
-        fn cost_singular(output : &Vector, expected : &Vector) -> f64 {
-            output
+        thread::scope(|scope| {
+            let function = &function;
+            let handles : vec::Vec<_> =
+                items
+                .chunks(chunk_size)
+                .enumerate()
+                .map(|(chunk_no, chunk)| scope.spawn(move || function(chunk_no, chunk)))
+                .collect();
+
+            handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+        })
+    }
+
+    /// Returns the size of the contiguous chunks work on a number of items is split into, so that
+    /// there is a chunk for each thread.
+    fn chunk_size(&self, len : usize) -> usize {
+        usize::max(len.div_ceil(self.threads), 1)
+    }
+
+    /// Calculates the cost for the network for a given input, using the provided loss function.
+    /// Any L1 or L2 penalty on the parameters of the network is added to the cost of each input.
+    pub fn cost(&self, output : &DataSet<T>, expected : &DataSet<T>, loss : &dyn Loss<T>) -> Result<vec::Vec<T>, NetworkError> {
+        Network::check_quantities("outputs for the expected outputs", expected, output)?;
+        self.check_entries("the outputs", self.outputs(), output)?;
+        self.check_entries("the expected outputs", self.outputs(), expected)?;
+
+        let penalty = T::from_f64(self.regularisation.penalty(&self.layers));
+
+        Ok(
+            output.0
             .iter()
-            .zip(expected.iter())
-            .map(|(a, b)| (a - b).powi(2))
-            .sum()
-        }
+            .zip(expected.0.iter())
+            .map(|(out, exp)| loss.cost(&out.0, &exp.0) + penalty)
+            .collect()
+        )
+    }
 
-        output.0
-        .iter()
-        .zip(expected.0.iter())
-        .map(|(out, exp)| cost_singular(out, exp))
-        .collect()
+    /// Backpropagates the network over one epoch of the provided data set, shuffling the order of
+    /// the inputs and updating the parameters once for each mini-batch of `batch_size` inputs,
+    /// using the gradient of the loss averaged over that mini-batch. Returns the number of updates
+    /// made. The optimizer's learning rate is left unchanged.
+    pub fn train_batch(&mut self, optimizer : &mut dyn Optimizer<T>, loss : &dyn Loss<T>, batch_size : usize, input : &DataSet<T>, expected : &DataSet<T>) -> Result<usize, NetworkError> {
+        self.train_epoch(optimizer, None, loss, batch_size, input, expected)
     }
 
-    /// Backpropagates the network and updates the weights and biases stochastically for a batch of inputs input.
-    pub fn train_batch(&mut self, weights_lr : f64, biases_lr : f64, input : &DataSet, expected : &DataSet) {
+    /// Trains the network over one epoch as `train_batch` does, setting the optimizer's learning
+    /// rate from the scheduler before each update and advancing the scheduler's step and epoch
+    /// counts. Schedules which adapt to the validation cost should be given it with
+    /// `Scheduler::observe` after each epoch.
+    pub fn train_batch_scheduled(&mut self, optimizer : &mut dyn Optimizer<T>, scheduler : &mut Scheduler, loss : &dyn Loss<T>, batch_size : usize, input : &DataSet<T>, expected : &DataSet<T>) -> Result<usize, NetworkError> {
+        self.train_epoch(optimizer, Some(scheduler), loss, batch_size, input, expected)
+    }
+
+    fn train_epoch(&mut self, optimizer : &mut dyn Optimizer<T>, mut scheduler : Option<&mut Scheduler>, loss : &dyn Loss<T>, batch_size : usize, input : &DataSet<T>, expected : &DataSet<T>) -> Result<usize, NetworkError> {
 
-        if input.quantity() != expected.quantity() {
-            panic!("Attempt to train a neural network with a different number of input data sets as output data sets.")
+        if self.mode != Mode::Training {
+            return Err(NetworkError::invalid_config("Attempt to train a neural network in inference mode."))
         }
-        if self.structure[0] != input.entries_per_set() {
-            panic!("Attempt to train a neural network with inputs of the innapropriate size given the number of neurons in the first layer.")
+        if batch_size == 0 {
+            return Err(NetworkError::invalid_config("Attempt to train a neural network with a mini-batch size of zero."))
         }
-        
-        for (input, expected) in input.0.iter().zip(expected.0.iter()) {
-            self.train_singular(weights_lr, biases_lr, input, expected);
+        Network::check_quantities("expected outputs for the inputs", input, expected)?;
+        self.check_entries("the inputs", self.inputs(), input)?;
+        self.check_entries("the expected outputs", self.outputs(), expected)?;
+
+        let mut order : vec::Vec<usize> = (0..input.quantity()).collect();
+        weights_gen::shuffle(&mut order, &mut self.rng);
+
+        // A mini-batch is split into at most one share per thread and one per input, so there is a
+        // replica for each of that many shares, and a thread for each after the first, which wait
+        // for their shares of each mini-batch in turn. A shorter final mini-batch can be split into
+        // more shares than the first.
+        let largest = usize::min(batch_size, order.len());
+        let replicas : vec::Vec<Replica<T>> =
+            (0..usize::min(self.threads, largest))
+            .map(|_| Mutex::new(self.layers.clone()))
+            .collect();
+        let reduction = Reduction::new();
+
+        thread::scope(|scope| {
+            let mut workers = Workers {
+                replicas : &replicas,
+                reduction : &reduction,
+                jobs : vec::Vec::new(),
+                done : vec::Vec::new(),
+                gradients : self.layers.iter().flat_map(|layer| layer.parameters()).map(|parameters| vec![T::ZERO; parameters.len()]).collect(),
+            };
+            for (share, replica) in replicas.iter().enumerate().skip(1) {
+                let (job_sender, jobs) = mpsc::channel::<(&[usize], ChaCha8Rng)>();
+                let (done, done_receiver) = mpsc::channel();
+                let reduction = &reduction;
+                scope.spawn(move || {
+                    for (indices, mut rng) in jobs {
+                        Network::backpropagate_share(replica, loss, indices, input, expected, &mut TrainContext::share(&mut rng, reduction, share));
+                        if done.send(()).is_err() {
+                            break
+                        }
+                    }
+                });
+                workers.jobs.push(job_sender);
+                workers.done.push(done_receiver);
+            }
+
+            for mini_batch in order.chunks(batch_size) {
+                if let Some(scheduler) = scheduler.as_deref_mut() {
+                    scheduler.apply(optimizer);
+                }
+                self.train_mini_batch(optimizer, loss, mini_batch, input, expected, &mut workers);
+                if let Some(scheduler) = scheduler.as_deref_mut() {
+                    scheduler.end_step();
+                }
+            }
+        });
+        if let Some(scheduler) = scheduler {
+            scheduler.end_epoch();
         }
+
+        Ok(order.len().div_ceil(batch_size))
     }
 
-    /// Backpropagates the network and updates the weights and biases for a single input.
-    fn train_singular(&mut self, weights_lr : f64, biases_lr : f64, input : &Vector, expected : &Vector) {
-        let feed_forward_results = self.feed_forward(input);
+    /// Backpropagates the network for the inputs at the specified indices of the data set, and
+    /// updates the parameters using the average gradient across them.
+    fn train_mini_batch<'a>(&mut self, optimizer : &mut dyn Optimizer<T>, loss : &dyn Loss<T>, indices : &'a [usize], input : &DataSet<T>, expected : &DataSet<T>, workers : &mut Workers<'a, T>) {
+        // Each share of the mini-batch is backpropagated through its own replica of the layers,
+        // with its own random number generator split from the network's, and the gradients are
+        // then summed in a fixed order so that results only depend on the number of threads.
+        let shares : vec::Vec<&[usize]> = indices.chunks(self.chunk_size(indices.len())).collect();
+        let mut rngs = weights_gen::split(&mut self.rng, self.threads).into_iter();
+        let mut first_rng = rngs.next().unwrap();
+        assert!(shares.len() <= workers.replicas.len(), "Attempt to split a mini-batch into {} shares with only {} replicas of the layers.", shares.len(), workers.replicas.len());
+        workers.reduction.start(shares.len());
+        for ((share, rng), jobs) in shares.iter().skip(1).zip(rngs).zip(&workers.jobs) {
+            jobs.send((share, rng)).expect("Attempt to send work to a training thread which panicked.");
+        }
+        Network::backpropagate_share(&workers.replicas[0], loss, shares[0], input, expected, &mut TrainContext::share(&mut first_rng, workers.reduction, 0));
+        for done in &workers.done[.. shares.len() - 1] {
+            done.recv().expect("Attempt to collect gradients from a training thread which panicked.");
+        }
+
+        let replicas : vec::Vec<_> = workers.replicas[.. shares.len()].iter().map(|replica| replica.lock().unwrap()).collect();
+        let scale = T::from_f64(1.0 / indices.len() as f64);
+
+        // State updated while training, such as running statistics, is of the whole mini-batch
+        // and so the same in every replica.
+        let states = self.layers.iter_mut().flat_map(|layer| layer.state_mut());
+        for (state, share_state) in states.zip(replicas[0].iter().flat_map(|layer| layer.state())) {
+            state.copy_from_slice(share_state);
+        }
 
-        // Calculate the difference to the weights and biases for all layers.
-        let activation_input_diff : VecDeque<Matrix> =
-            self.activation_input_diff(&feed_forward_results, expected, 1);
-        let biases_diff = &activation_input_diff;
-        let weights_diff = self.weight_diff(&activation_input_diff, &feed_forward_results); 
+        for (share_no, replica) in replicas.iter().enumerate() {
+            let share_gradients = replica.iter().flat_map(|layer| layer.gradients());
+            for (gradient, share_gradient) in workers.gradients.iter_mut().zip(share_gradients) {
+                if share_no == 0 {
+                    gradient.copy_from_slice(share_gradient);
+                }
+                else {
+                    T::axpy(T::ONE, share_gradient, gradient);
+                }
+            }
+        }
+        // The replicas are unlocked so that the updated parameters can be copied into them.
+        drop(replicas);
+
+        // Each set of parameters is identified to the optimizer by its position across the layers,
+        // so the weights and biases of the nth dense layer of a network of dense and activation
+        // layers have ids 2n and 2n + 1.
+        optimizer.next_step();
+        let kinds : vec::Vec<ParameterKind> = self.layers.iter().flat_map(|layer| layer.parameter_kinds()).collect();
+        let parameters = self.layers.iter_mut().flat_map(|layer| layer.parameters_mut());
+        for (id, ((parameters, gradient), kind)) in parameters.zip(workers.gradients.iter_mut()).zip(kinds).enumerate() {
+            for value in gradient.iter_mut() {
+                *value *= scale;
+            }
+            self.regularisation.add_gradient(kind, parameters, gradient);
+            optimizer.update(id, parameters, gradient);
+        }
 
-       
-        for param_set in 0..(self.num_layers() - 1) {
-            self.weights[param_set] = &self.weights[param_set] - &(weights_lr * &weights_diff[param_set]);
-            self.biases[param_set] = &self.biases[param_set] - &(biases_lr * &biases_diff[param_set].clone().into_vector());
+        self.regularisation.constrain(&mut self.layers);
+
+        for replica in workers.replicas {
+            let mut replica = replica.lock().unwrap();
+            for (layer, replica_layer) in self.layers.iter().zip(replica.iter_mut()) {
+                for (values, replica_values) in layer.parameters().into_iter().zip(replica_layer.parameters_mut()) {
+                    replica_values.copy_from_slice(values);
+                }
+                for (values, replica_values) in layer.state().into_iter().zip(replica_layer.state_mut()) {
+                    replica_values.copy_from_slice(values);
+                }
+            }
         }
     }
 
-    /// Calculates the derivative of the network cost with respect to the input of the activation
-    /// function for each layer. The resulting VecDeque is indexed from 0 starting at the second
-    /// layer in the network. This should be called with an initial value of 1.
-    fn activation_input_diff(&self, feed_forward_results : &(Vector, vec::Vec<FeedForwardResult>), expected : &Vector, layer_no : usize) -> VecDeque<Matrix> {
+    /// Backpropagates the inputs at the specified indices of the data set through a replica of the
+    /// layers.
+    fn backpropagate_share(replica : &Replica<T>, loss : &dyn Loss<T>, indices : &[usize], input : &DataSet<T>, expected : &DataSet<T>, context : &mut TrainContext) {
+        // The other shares must not be left waiting for this one if it panics.
+        let _poison = context.reduction().map(PoisonOnPanic);
+        let mut layers = replica.lock().unwrap();
+        Network::backpropagate(&mut layers, loss, input.internal_gather(indices), &expected.internal_gather(indices), context);
+    }
 
-        // Last layer in the network.
-        if layer_no == self.num_layers() - 1 {
-            let cost_diff =
-                (2.0 * &(&feed_forward_results.1[layer_no - 1].after_activ - expected))
-                .into_matrix()
-                .transpose();
+    /// Feeds a batch of inputs forward through the layers and backpropagates the derivative of the
+    /// loss, leaving each layer with the gradients of its parameters summed over the batch.
+    fn backpropagate(layers : &mut [Box<dyn Layer<T>>], loss : &dyn Loss<T>, input : Matrix<T>, expected : &Matrix<T>, context : &mut TrainContext) {
+        let output =
+            layers
+            .iter_mut()
+            .enumerate()
+            .fold(input, |activations, (layer_no, layer)| {
+                let output = layer.forward_train(&activations, context);
+                Network::check_shape(layer_no, layer.as_ref(), "outputs", activations.rows(), layer.outputs(), &output);
+                output
+            });
+
+        // A softmax output layer is backpropagated together with the loss, as their combined
+        // derivative is simpler and more stable than that of either alone.
+        let (mut gradient, hidden_layers) = match layers.split_last_mut() {
+            Some((last, rest)) if last.activation() == Some(Activation::Softmax) =>
+                (Matrix::zip_map_rows(&output, expected, |out, exp| loss.softmax_gradient(out, exp)), rest),
+            _ =>
+                (Matrix::zip_map_rows(&output, expected, |out, exp| loss.gradient(out, exp)), layers),
+        };
+
+        for layer_no in (0..hidden_layers.len()).rev() {
+            let layer = &mut hidden_layers[layer_no];
+            match layer.backward(&gradient, layer_no > 0, context) {
+                Some(input_gradient) => {
+                    Network::check_shape(layer_no, layer.as_ref(), "input gradients", gradient.rows(), layer.inputs(), &input_gradient);
+                    gradient = input_gradient
+                },
+                None => break
+            }
+        }
+    }
+}
 
-            let activation_derivative =
-                Matrix::diagonal(
-                    &feed_forward_results.1[layer_no - 1].after_biases.map(self.activ_diff)
-                );
-            
-            let activation_input_diff =
-                &cost_diff * &activation_derivative;
+#[cfg(test)]
+mod tests {
+    use crate::{DataSet, Network};
+    use crate::activation::Activation;
+    use crate::algebra::{Vector, Matrix};
+    use crate::layer::{Layer, TrainContext};
+    use crate::float::Float;
+    use crate::loss::MeanSquaredError;
+    use crate::optimizer::Sgd;
+
+    /// Creates a data set of `quantity` sets of `entries` values each.
+    fn data_set<T : Float>(quantity : usize, entries : usize, offset : f64) -> DataSet<T> {
+        DataSet(
+            (0..quantity)
+            .map(|set| Vector::new((0..entries).map(|entry| T::from_f64((((set * entries + entry) as f64 + offset) * 0.37).sin())).collect()))
+            .collect()
+        )
+    }
 
-            let mut diffs = VecDeque::with_capacity(self.num_layers() - 1); 
-            diffs.push_front(activation_input_diff);
-            diffs
+    /// Trains a seeded network for a few epochs over a data set which, unless `quantity` is a
+    /// multiple of `batch_size`, is not a whole number of mini-batches, so the last mini-batch can
+    /// be split between more threads than the first.
+    fn trained<T : Float>(quantity : usize, threads : usize, batch_size : usize, batch_norm : bool, dropout : bool) -> Network<T> {
+        let mut builder = Network::<T>::builder().input(3).dense(4, Activation::Tanh);
+        if batch_norm {
+            builder = builder.batch_norm();
         }
-        else {
-            let mut proceeding_layers = self.activation_input_diff(feed_forward_results, expected, layer_no + 1);
-
-            let activation_derivative =
-                Matrix::diagonal(
-                    &feed_forward_results.1[layer_no - 1].after_biases.map(self.activ_diff)
-                );
-
-            let diff = proceeding_layers.front().unwrap() * &(&self.weights[layer_no] * &activation_derivative);
-            proceeding_layers.push_front(diff);
-            proceeding_layers
-        }
-    }
-
-    /// Calculates the derivative of the weights with respect to the input to the activation
-    /// function of the following layer.
-    fn weight_diff(&self, activation_input_diff : &VecDeque<Matrix>, feed_forward_results : &(Vector, Vec<FeedForwardResult>)) -> vec::Vec<Matrix> {
-        let mut diffs = vec::Vec::with_capacity(self.num_layers() - 1);
-        
-        for weight_set in 0..(self.num_layers() - 1) {
-            diffs.push(
-                Vector::outer_product(
-                    &activation_input_diff[weight_set].clone().into_vector(),
-                    if weight_set == 0 {
-                        &feed_forward_results.0
-                    }
-                    else {
-                        &feed_forward_results.1[weight_set - 1].after_activ
+        if dropout {
+            builder = builder.dropout(0.25);
+        }
+        let mut network = builder.dense(2, Activation::Linear).threads(threads).build(7).unwrap();
+
+        let (input, expected) = (data_set(quantity, 3, 0.0), data_set(quantity, 2, 0.5));
+        let mut optimizer = Sgd::new(0.1).unwrap();
+        for _ in 0..3 {
+            let updates = network.train_batch(&mut optimizer, &MeanSquaredError, batch_size, &input, &expected).unwrap();
+            assert_eq!(updates, quantity.div_ceil(batch_size));
+        }
+
+        network
+    }
+
+    #[test]
+    fn trains_final_short_batch_across_threads() {
+        trained::<f32>(9, 4, 5, false, false);
+        trained::<f32>(44, 12, 32, false, false);
+    }
+
+    #[test]
+    fn trains_final_short_batch_across_threads_with_batch_norm() {
+        trained::<f32>(9, 4, 5, true, false);
+        trained::<f32>(44, 12, 32, true, false);
+    }
+
+    /// Splitting mini-batches between threads only changes the order the gradients and batch
+    /// statistics are summed in, so the results agree with a single thread up to rounding.
+    #[test]
+    fn threads_agree_with_single_thread() {
+        let input = data_set::<f64>(10, 3, 1.0);
+        for batch_norm in [false, true] {
+            for (quantity, threads, batch_size) in [(9, 4, 5), (44, 12, 32), (40, 3, 8)] {
+                let single = trained::<f64>(quantity, 1, batch_size, batch_norm, false).test(&input).unwrap();
+                let split = trained::<f64>(quantity, threads, batch_size, batch_norm, false).test(&input).unwrap();
+
+                for set in 0..input.quantity() {
+                    for (single, split) in single.get(set).iter().zip(split.get(set)) {
+                        assert!((single - split).abs() <= 1e-12, "a single thread gave {} but {} threads gave {}", single, threads, split);
                     }
-                )
-            );
+                }
+            }
+        }
+    }
+
+    /// Training with the same seed and number of threads is reproducible, including the dropout
+    /// masks drawn by each thread.
+    #[test]
+    fn training_is_deterministic() {
+        for threads in [1, 4] {
+            let first = trained::<f64>(44, threads, 8, true, true);
+            let second = trained::<f64>(44, threads, 8, true, true);
+
+            assert_eq!(first.serialise().unwrap(), second.serialise().unwrap());
+        }
+    }
+
+    /// A layer which declares one output but returns a column for each of its inputs.
+    #[derive(Debug, Clone)]
+    struct Misshapen;
+
+    impl Layer<f32> for Misshapen {
+        fn name(&self) -> &str {
+            "misshapen"
+        }
+
+        fn inputs(&self) -> usize {
+            3
+        }
+
+        fn outputs(&self) -> usize {
+            1
         }
 
-        diffs
+        fn forward(&self, input : &Matrix<f32>) -> Matrix<f32> {
+            input.clone()
+        }
+
+        fn forward_train(&mut self, input : &Matrix<f32>, _context : &mut TrainContext) -> Matrix<f32> {
+            input.clone()
+        }
+
+        fn backward(&mut self, output_gradient : &Matrix<f32>, _propagate : bool, _context : &mut TrainContext) -> Option<Matrix<f32>> {
+            Some(output_gradient.clone())
+        }
+
+        fn clone_box(&self) -> Box<dyn Layer<f32>> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Layer 1 (misshapen) returned outputs of size 4x3 instead of 4x1.")]
+    fn rejects_outputs_of_the_wrong_shape() {
+        let network = Network::<f32>::builder().input(3).layer(Box::new(Misshapen)).build(7).unwrap();
+        let _ = network.test(&data_set(4, 3, 0.0));
     }
 }
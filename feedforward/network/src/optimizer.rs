@@ -0,0 +1,591 @@
+use crate::error::NetworkError;
+use crate::float::Float;
+
+/// Updates the parameters of a network from the gradient of the cost with respect to them. Each
+/// set of parameters (the weights or biases of a layer) is identified by a unique `id`, which
+/// optimizers use to keep track of any per-parameter state across updates. Optimizers are
+/// implemented for each precision of network, but always calculate updates and keep their state in
+/// double precision.
+pub trait Optimizer<T : Float = f64> {
+    /// Returns the name of the type of optimizer, which checkpoints record so that their state is
+    /// only loaded into the same type.
+    fn name(&self) -> &'static str;
+
+    /// Called once before each update of all the network's parameters.
+    fn next_step(&mut self) {}
+
+    /// Updates a set of parameters given the gradient of the cost with respect to them.
+    fn update(&mut self, id : usize, parameters : &mut [T], gradient : &[T]);
+
+    /// Returns the learning rate used by the next update.
+    fn learning_rate(&self) -> f64;
+
+    /// Sets the learning rate, which learning rate schedules adjust as training progresses.
+    fn set_learning_rate(&mut self, learning_rate : f64);
+
+    /// Returns the internal state of the optimizer, so that it can be saved in a checkpoint.
+    fn state(&self) -> Vec<Vec<f64>> {
+        Vec::new()
+    }
+
+    /// Restores internal state previously returned by `state`, checking it against `sizes`, the
+    /// number of parameters in each set of the network it will train, indexed by id.
+    fn load_state(&mut self, state : Vec<Vec<f64>>, _sizes : &[usize]) -> Result<(), NetworkError> {
+        if state.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(NetworkError::invalid_config("Attempt to load state into an optimizer which does not have any."))
+        }
+    }
+}
+
+/// Checks that per-parameter state being loaded into an optimizer has an entry of the right length
+/// for each set of parameters it has been initialised for.
+fn check_param_states(optimizer : &str, states : &[Vec<f64>], sizes : &[usize]) -> Result<(), NetworkError> {
+    if states.len() > sizes.len() {
+        return Err(NetworkError::invalid_config(format!("Attempt to load state for {} sets of parameters into {} optimizer for a network with {}.", states.len(), optimizer, sizes.len())))
+    }
+    for (id, (state, size)) in states.iter().zip(sizes.iter()).enumerate() {
+        if !state.is_empty() && state.len() != *size {
+            return Err(NetworkError::invalid_config(format!("Attempt to load state for {} parameters with id {} into {} optimizer for a network which has {}.", state.len(), id, optimizer, size)))
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that a learning rate is neither negative nor infinite.
+fn check_learning_rate(optimizer : &str, learning_rate : f64) -> Result<(), NetworkError> {
+    if !(0.0..f64::INFINITY).contains(&learning_rate) {
+        return Err(NetworkError::invalid_config(format!("Attempt to use {} optimizer with a learning rate of {}, which is not a non-negative number.", optimizer, learning_rate)))
+    }
+
+    Ok(())
+}
+
+/// Checks that the weight given to the previous value of a moving average, such as a momentum or
+/// decay rate, is at least zero and less than one.
+fn check_moving_average(optimizer : &str, description : &str, weight : f64) -> Result<(), NetworkError> {
+    if !(0.0..1.0).contains(&weight) {
+        return Err(NetworkError::invalid_config(format!("Attempt to use {} optimizer with a {} of {}, which is not at least zero and less than one.", optimizer, description, weight)))
+    }
+
+    Ok(())
+}
+
+/// Checks that the value added to a denominator so that it is never zero is positive.
+fn check_epsilon(optimizer : &str, epsilon : f64) -> Result<(), NetworkError> {
+    if epsilon.is_nan() || epsilon <= 0.0 {
+        return Err(NetworkError::invalid_config(format!("Attempt to use {} optimizer with an epsilon of {}, which is not positive.", optimizer, epsilon)))
+    }
+
+    Ok(())
+}
+
+/// Returns the state for the set of parameters with the specified id, initialising it to zero the
+/// first time it is requested.
+fn param_state(states : &mut Vec<Vec<f64>>, id : usize, len : usize) -> &mut Vec<f64> {
+    if states.len() <= id {
+        states.resize(id + 1, Vec::new());
+    }
+    if states[id].is_empty() {
+        states[id] = vec![0.0; len];
+    }
+
+    debug_assert!(states[id].len() == len);
+    &mut states[id]
+}
+
+/// Plain stochastic gradient descent.
+#[derive(Debug, Clone)]
+pub struct Sgd {
+    learning_rate : f64,
+}
+
+impl Sgd {
+    pub fn new(learning_rate : f64) -> Result<Sgd, NetworkError> {
+        check_learning_rate("an SGD", learning_rate)?;
+
+        Ok(Sgd { learning_rate })
+    }
+}
+
+impl<T : Float> Optimizer<T> for Sgd {
+    fn name(&self) -> &'static str {
+        "sgd"
+    }
+
+    fn update(&mut self, _id : usize, parameters : &mut [T], gradient : &[T]) {
+        for (p, g) in parameters.iter_mut().zip(gradient.iter()) {
+            *p = T::from_f64(p.to_f64() - self.learning_rate * g.to_f64());
+        }
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate : f64) {
+        self.learning_rate = learning_rate;
+    }
+}
+
+/// Gradient descent with classical momentum, accumulating a velocity for each parameter.
+#[derive(Debug, Clone)]
+pub struct Momentum {
+    learning_rate : f64,
+    momentum : f64,
+    velocity : Vec<Vec<f64>>,
+}
+
+impl Momentum {
+    /// Creates the optimizer, with a momentum which must be at least zero and less than one.
+    pub fn new(learning_rate : f64, momentum : f64) -> Result<Momentum, NetworkError> {
+        check_learning_rate("a momentum", learning_rate)?;
+        check_moving_average("a momentum", "momentum", momentum)?;
+
+        Ok(Momentum { learning_rate, momentum, velocity : Vec::new() })
+    }
+
+    /// Returns the weight given to the previous velocity.
+    pub fn momentum(&self) -> f64 {
+        self.momentum
+    }
+}
+
+impl<T : Float> Optimizer<T> for Momentum {
+    fn name(&self) -> &'static str {
+        "momentum"
+    }
+
+    fn update(&mut self, id : usize, parameters : &mut [T], gradient : &[T]) {
+        let velocity = param_state(&mut self.velocity, id, parameters.len());
+
+        for ((p, g), v) in parameters.iter_mut().zip(gradient.iter()).zip(velocity.iter_mut()) {
+            *v = self.momentum * *v - self.learning_rate * g.to_f64();
+            *p = T::from_f64(p.to_f64() + *v);
+        }
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate : f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn state(&self) -> Vec<Vec<f64>> {
+        self.velocity.clone()
+    }
+
+    fn load_state(&mut self, state : Vec<Vec<f64>>, sizes : &[usize]) -> Result<(), NetworkError> {
+        check_param_states("a momentum", &state, sizes)?;
+        self.velocity = state;
+        Ok(())
+    }
+}
+
+/// Gradient descent with Nesterov accelerated momentum. This uses the reformulation in which the
+/// parameters are stored at their look-ahead position, so the gradient is evaluated as normal.
+#[derive(Debug, Clone)]
+pub struct Nesterov {
+    learning_rate : f64,
+    momentum : f64,
+    velocity : Vec<Vec<f64>>,
+}
+
+impl Nesterov {
+    /// Creates the optimizer, with a momentum which must be at least zero and less than one.
+    pub fn new(learning_rate : f64, momentum : f64) -> Result<Nesterov, NetworkError> {
+        check_learning_rate("a Nesterov", learning_rate)?;
+        check_moving_average("a Nesterov", "momentum", momentum)?;
+
+        Ok(Nesterov { learning_rate, momentum, velocity : Vec::new() })
+    }
+
+    /// Returns the weight given to the previous velocity.
+    pub fn momentum(&self) -> f64 {
+        self.momentum
+    }
+}
+
+impl<T : Float> Optimizer<T> for Nesterov {
+    fn name(&self) -> &'static str {
+        "nesterov"
+    }
+
+    fn update(&mut self, id : usize, parameters : &mut [T], gradient : &[T]) {
+        let velocity = param_state(&mut self.velocity, id, parameters.len());
+
+        for ((p, g), v) in parameters.iter_mut().zip(gradient.iter()).zip(velocity.iter_mut()) {
+            let g = g.to_f64();
+            *v = self.momentum * *v - self.learning_rate * g;
+            *p = T::from_f64(p.to_f64() + (self.momentum * *v - self.learning_rate * g));
+        }
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate : f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn state(&self) -> Vec<Vec<f64>> {
+        self.velocity.clone()
+    }
+
+    fn load_state(&mut self, state : Vec<Vec<f64>>, sizes : &[usize]) -> Result<(), NetworkError> {
+        check_param_states("a Nesterov", &state, sizes)?;
+        self.velocity = state;
+        Ok(())
+    }
+}
+
+/// RMSProp, which scales the learning rate of each parameter by a moving average of the magnitude
+/// of its recent gradients.
+#[derive(Debug, Clone)]
+pub struct RmsProp {
+    learning_rate : f64,
+    decay : f64,
+    epsilon : f64,
+    mean_square : Vec<Vec<f64>>,
+}
+
+impl RmsProp {
+    /// Creates the optimizer with a decay of 0.9 and an epsilon of 1e-8.
+    pub fn new(learning_rate : f64) -> Result<RmsProp, NetworkError> {
+        check_learning_rate("an RMSProp", learning_rate)?;
+
+        Ok(RmsProp { learning_rate, decay : 0.9, epsilon : 1e-8, mean_square : Vec::new() })
+    }
+
+    /// Sets the weight given to the previous mean square of each gradient, which must be at least
+    /// zero and less than one.
+    pub fn with_decay(mut self, decay : f64) -> Result<RmsProp, NetworkError> {
+        check_moving_average("an RMSProp", "decay", decay)?;
+
+        self.decay = decay;
+        Ok(self)
+    }
+
+    /// Sets the value added to the root mean square before dividing by it, which must be positive.
+    pub fn with_epsilon(mut self, epsilon : f64) -> Result<RmsProp, NetworkError> {
+        check_epsilon("an RMSProp", epsilon)?;
+
+        self.epsilon = epsilon;
+        Ok(self)
+    }
+
+    /// Returns the weight given to the previous mean square of each gradient.
+    pub fn decay(&self) -> f64 {
+        self.decay
+    }
+
+    /// Returns the value added to the root mean square before dividing by it.
+    pub fn epsilon(&self) -> f64 {
+        self.epsilon
+    }
+}
+
+impl<T : Float> Optimizer<T> for RmsProp {
+    fn name(&self) -> &'static str {
+        "rmsprop"
+    }
+
+    fn update(&mut self, id : usize, parameters : &mut [T], gradient : &[T]) {
+        let mean_square = param_state(&mut self.mean_square, id, parameters.len());
+
+        for ((p, g), s) in parameters.iter_mut().zip(gradient.iter()).zip(mean_square.iter_mut()) {
+            let g = g.to_f64();
+            *s = self.decay * *s + (1.0 - self.decay) * g * g;
+            *p = T::from_f64(p.to_f64() - self.learning_rate * g / (s.sqrt() + self.epsilon));
+        }
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate : f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn state(&self) -> Vec<Vec<f64>> {
+        self.mean_square.clone()
+    }
+
+    fn load_state(&mut self, state : Vec<Vec<f64>>, sizes : &[usize]) -> Result<(), NetworkError> {
+        check_param_states("an RMSProp", &state, sizes)?;
+        self.mean_square = state;
+        Ok(())
+    }
+}
+
+/// Adam, which keeps bias-corrected moving averages of both the gradient and its square for each
+/// parameter.
+#[derive(Debug, Clone)]
+pub struct Adam {
+    learning_rate : f64,
+    beta1 : f64,
+    beta2 : f64,
+    epsilon : f64,
+    step : i32,
+    first_moment : Vec<Vec<f64>>,
+    second_moment : Vec<Vec<f64>>,
+}
+
+impl Adam {
+    /// Creates the optimizer with betas of 0.9 and 0.999 and an epsilon of 1e-8.
+    pub fn new(learning_rate : f64) -> Result<Adam, NetworkError> {
+        check_learning_rate("an Adam", learning_rate)?;
+
+        Ok(Adam {
+            learning_rate,
+            beta1 : 0.9,
+            beta2 : 0.999,
+            epsilon : 1e-8,
+            step : 0,
+            first_moment : Vec::new(),
+            second_moment : Vec::new()
+        })
+    }
+
+    /// Sets the weights given to the previous moving averages of the gradient and its square,
+    /// which must be at least zero and less than one.
+    pub fn with_betas(mut self, beta1 : f64, beta2 : f64) -> Result<Adam, NetworkError> {
+        check_moving_average("an Adam", "beta1", beta1)?;
+        check_moving_average("an Adam", "beta2", beta2)?;
+
+        self.beta1 = beta1;
+        self.beta2 = beta2;
+        Ok(self)
+    }
+
+    /// Sets the value added to the root of the second moment before dividing by it, which must be
+    /// positive.
+    pub fn with_epsilon(mut self, epsilon : f64) -> Result<Adam, NetworkError> {
+        check_epsilon("an Adam", epsilon)?;
+
+        self.epsilon = epsilon;
+        Ok(self)
+    }
+
+    /// Returns the weights given to the previous moving averages of the gradient and its square.
+    pub fn betas(&self) -> (f64, f64) {
+        (self.beta1, self.beta2)
+    }
+
+    /// Returns the value added to the root of the second moment before dividing by it.
+    pub fn epsilon(&self) -> f64 {
+        self.epsilon
+    }
+
+    /// Applies the Adam update, optionally with decoupled weight decay.
+    fn adam_update<T : Float>(&mut self, id : usize, parameters : &mut [T], gradient : &[T], weight_decay : f64) {
+        let first_correction = 1.0 - self.beta1.powi(self.step);
+        let second_correction = 1.0 - self.beta2.powi(self.step);
+
+        let len = parameters.len();
+        param_state(&mut self.first_moment, id, len);
+        param_state(&mut self.second_moment, id, len);
+        let first_moment = &mut self.first_moment[id];
+        let second_moment = &mut self.second_moment[id];
+
+        for (i, (p, g)) in parameters.iter_mut().zip(gradient.iter()).enumerate() {
+            let (parameter, g) = (p.to_f64(), g.to_f64());
+            first_moment[i] = self.beta1 * first_moment[i] + (1.0 - self.beta1) * g;
+            second_moment[i] = self.beta2 * second_moment[i] + (1.0 - self.beta2) * g * g;
+
+            let m = first_moment[i] / first_correction;
+            let v = second_moment[i] / second_correction;
+
+            *p = T::from_f64(parameter - self.learning_rate * (m / (v.sqrt() + self.epsilon) + weight_decay * parameter));
+        }
+    }
+
+    /// The state is the step count, followed by the first and second moments for each id in turn.
+    fn adam_state(&self) -> Vec<Vec<f64>> {
+        let mut state = vec![vec![self.step as f64]];
+        for (first, second) in self.first_moment.iter().zip(self.second_moment.iter()) {
+            state.push(first.clone());
+            state.push(second.clone());
+        }
+        state
+    }
+
+    fn load_adam_state(&mut self, mut state : Vec<Vec<f64>>, sizes : &[usize]) -> Result<(), NetworkError> {
+        if state.is_empty() || state[0].len() != 1 || state.len().is_multiple_of(2) {
+            return Err(NetworkError::invalid_config("Attempt to load malformed state into an Adam optimizer."))
+        }
+        // The step is a count of updates, and a negative one would zero the bias corrections.
+        let step = state[0][0];
+        if !(0.0..=i32::MAX as f64).contains(&step) || step.fract() != 0.0 {
+            return Err(NetworkError::invalid_config(format!("Attempt to load a step of {} into an Adam optimizer, which is not a count of updates.", step)))
+        }
+
+        let moments = state.split_off(1);
+        let first_moment : Vec<Vec<f64>> = moments.iter().step_by(2).cloned().collect();
+        let second_moment : Vec<Vec<f64>> = moments.iter().skip(1).step_by(2).cloned().collect();
+        check_param_states("an Adam", &first_moment, sizes)?;
+        check_param_states("an Adam", &second_moment, sizes)?;
+
+        self.step = step as i32;
+        self.first_moment = first_moment;
+        self.second_moment = second_moment;
+        Ok(())
+    }
+}
+
+impl<T : Float> Optimizer<T> for Adam {
+    fn name(&self) -> &'static str {
+        "adam"
+    }
+
+    fn next_step(&mut self) {
+        self.step += 1;
+    }
+
+    fn update(&mut self, id : usize, parameters : &mut [T], gradient : &[T]) {
+        self.adam_update(id, parameters, gradient, 0.0);
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate : f64) {
+        self.learning_rate = learning_rate;
+    }
+
+    fn state(&self) -> Vec<Vec<f64>> {
+        self.adam_state()
+    }
+
+    fn load_state(&mut self, state : Vec<Vec<f64>>, sizes : &[usize]) -> Result<(), NetworkError> {
+        self.load_adam_state(state, sizes)
+    }
+}
+
+/// Adam with weight decay decoupled from the gradient, so that the decay is not scaled by the
+/// adaptive learning rate.
+#[derive(Debug, Clone)]
+pub struct AdamW {
+    weight_decay : f64,
+    adam : Adam,
+}
+
+impl AdamW {
+    /// Creates the optimizer with the defaults of `Adam` and a weight decay which must not be
+    /// negative.
+    pub fn new(learning_rate : f64, weight_decay : f64) -> Result<AdamW, NetworkError> {
+        if !(0.0..f64::INFINITY).contains(&weight_decay) {
+            return Err(NetworkError::invalid_config(format!("Attempt to use an AdamW optimizer with a weight decay of {}, which is not a non-negative number.", weight_decay)))
+        }
+
+        Ok(AdamW { weight_decay, adam : Adam::new(learning_rate)? })
+    }
+
+    /// Sets the betas as `Adam::with_betas` does.
+    pub fn with_betas(mut self, beta1 : f64, beta2 : f64) -> Result<AdamW, NetworkError> {
+        self.adam = self.adam.with_betas(beta1, beta2)?;
+        Ok(self)
+    }
+
+    /// Sets the epsilon as `Adam::with_epsilon` does.
+    pub fn with_epsilon(mut self, epsilon : f64) -> Result<AdamW, NetworkError> {
+        self.adam = self.adam.with_epsilon(epsilon)?;
+        Ok(self)
+    }
+
+    /// Returns the weight decay applied at each update, before scaling by the learning rate.
+    pub fn weight_decay(&self) -> f64 {
+        self.weight_decay
+    }
+
+    /// Returns the weights given to the previous moving averages of the gradient and its square.
+    pub fn betas(&self) -> (f64, f64) {
+        self.adam.betas()
+    }
+
+    /// Returns the value added to the root of the second moment before dividing by it.
+    pub fn epsilon(&self) -> f64 {
+        self.adam.epsilon()
+    }
+}
+
+impl<T : Float> Optimizer<T> for AdamW {
+    fn name(&self) -> &'static str {
+        "adamw"
+    }
+
+    fn next_step(&mut self) {
+        self.adam.step += 1;
+    }
+
+    fn update(&mut self, id : usize, parameters : &mut [T], gradient : &[T]) {
+        self.adam.adam_update(id, parameters, gradient, self.weight_decay);
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.adam.learning_rate
+    }
+
+    fn set_learning_rate(&mut self, learning_rate : f64) {
+        self.adam.learning_rate = learning_rate;
+    }
+
+    fn state(&self) -> Vec<Vec<f64>> {
+        self.adam.adam_state()
+    }
+
+    fn load_state(&mut self, state : Vec<Vec<f64>>, sizes : &[usize]) -> Result<(), NetworkError> {
+        self.adam.load_adam_state(state, sizes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Optimizer, Sgd, Momentum, Nesterov, RmsProp, Adam, AdamW};
+    use crate::error::NetworkError;
+
+    #[test]
+    fn rejects_invalid_adam_step() {
+        for step in [-1.0, 2.5, f64::NAN, f64::INFINITY, 1e10] {
+            let mut optimizer = Adam::new(0.01).unwrap();
+            let loaded = Optimizer::<f64>::load_state(&mut optimizer, vec![vec![step], vec![0.0], vec![1.0]], &[1]);
+            assert!(matches!(loaded, Err(NetworkError::InvalidConfig(_))), "loaded a step of {}", step);
+        }
+
+        let mut optimizer = Adam::new(0.01).unwrap();
+        assert!(Optimizer::<f64>::load_state(&mut optimizer, vec![vec![3.0], vec![0.0], vec![1.0]], &[1]).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_hyperparameters() {
+        let invalid = |result : Result<(), NetworkError>| matches!(result, Err(NetworkError::InvalidConfig(_)));
+
+        for rate in [-0.1, f64::NAN, f64::INFINITY] {
+            assert!(invalid(Sgd::new(rate).map(drop)));
+            assert!(invalid(Adam::new(rate).map(drop)));
+        }
+        for momentum in [-0.1, 1.0, f64::NAN] {
+            assert!(invalid(Momentum::new(0.01, momentum).map(drop)));
+            assert!(invalid(Nesterov::new(0.01, momentum).map(drop)));
+            assert!(invalid(RmsProp::new(0.01).and_then(|optimizer| optimizer.with_decay(momentum)).map(drop)));
+            assert!(invalid(Adam::new(0.01).and_then(|optimizer| optimizer.with_betas(momentum, 0.999)).map(drop)));
+            assert!(invalid(Adam::new(0.01).and_then(|optimizer| optimizer.with_betas(0.9, momentum)).map(drop)));
+        }
+        for epsilon in [0.0, -1e-8, f64::NAN] {
+            assert!(invalid(RmsProp::new(0.01).and_then(|optimizer| optimizer.with_epsilon(epsilon)).map(drop)));
+            assert!(invalid(AdamW::new(0.01, 0.0).and_then(|optimizer| optimizer.with_epsilon(epsilon)).map(drop)));
+        }
+        assert!(invalid(AdamW::new(0.01, -0.1).map(drop)));
+
+        assert!(Momentum::new(0.0, 0.0).is_ok());
+        assert!(AdamW::new(0.01, 0.0).and_then(|optimizer| optimizer.with_betas(0.0, 0.5)).is_ok());
+    }
+}
@@ -0,0 +1,146 @@
+use crate::error::NetworkError;
+use crate::float::Float;
+use crate::layer::{Layer, ParameterKind};
+use crate::save::{Lines, expect_line, parse};
+
+/// Penalties on the size of a network's weights, which discourage overfitting. The L1 penalty is
+/// `l1` times the sum of the absolute values of the weights and the L2 penalty is `l2 / 2` times
+/// the sum of their squares, so that its gradient is `l2` times each weight. Both are added to the
+/// cost of each input, and their gradients to the average gradient of each mini-batch. Biases are
+/// only penalised if `with_biases` is set.
+///
+/// A max-norm constraint can also be set, which after each update rescales the incoming weights of
+/// any neuron whose Euclidean norm exceeds it.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Regularisation {
+    l1 : f64,
+    l2 : f64,
+    biases : bool,
+    max_norm : Option<f64>,
+}
+
+impl Regularisation {
+    /// Creates a regularisation which does nothing, to be configured with the other methods.
+    pub fn new() -> Regularisation {
+        Regularisation::default()
+    }
+
+    /// Sets the coefficient of the L1 penalty.
+    pub fn with_l1(mut self, l1 : f64) -> Regularisation {
+        self.l1 = l1;
+        self
+    }
+
+    /// Sets the coefficient of the L2 penalty.
+    pub fn with_l2(mut self, l2 : f64) -> Regularisation {
+        self.l2 = l2;
+        self
+    }
+
+    /// Sets whether the penalties also apply to biases.
+    pub fn with_biases(mut self, biases : bool) -> Regularisation {
+        self.biases = biases;
+        self
+    }
+
+    /// Sets the largest norm allowed for the incoming weights of each neuron.
+    pub fn with_max_norm(mut self, max_norm : f64) -> Regularisation {
+        self.max_norm = Some(max_norm);
+        self
+    }
+
+    /// Checks that the coefficients are not negative and that any max-norm is positive.
+    pub (crate) fn validate(&self) -> Result<(), NetworkError> {
+        if !(0.0..).contains(&self.l1) || !(0.0..).contains(&self.l2) {
+            return Err(NetworkError::invalid_config(format!("Attempt to use L1 and L2 penalties of {} and {}, which must not be negative.", self.l1, self.l2)))
+        }
+        if let Some(max_norm) = self.max_norm {
+            if max_norm.is_nan() || max_norm <= 0.0 {
+                return Err(NetworkError::invalid_config(format!("Attempt to use a max-norm of {}, which must be positive.", max_norm)))
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the regularisation as a line of a checkpoint.
+    pub (crate) fn serialise(&self) -> String {
+        let max_norm = match self.max_norm {
+            Some(max_norm) => max_norm.to_string(),
+            None => String::from("none"),
+        };
+
+        format!("regularisation {} {} {} {}\n", self.l1, self.l2, self.biases, max_norm)
+    }
+
+    /// Reads a regularisation written by `serialise`.
+    pub (crate) fn deserialise(lines : &mut Lines) -> Result<Regularisation, NetworkError> {
+        let mut entries = expect_line(lines, "regularisation")?;
+        let l1 : f64 = parse(&mut entries, "L1 penalty")?;
+        let l2 : f64 = parse(&mut entries, "L2 penalty")?;
+        let biases : bool = parse(&mut entries, "whether biases are penalised")?;
+        let max_norm = match parse::<String>(&mut entries, "max-norm")?.as_str() {
+            "none" => None,
+            max_norm => match max_norm.parse::<f64>() {
+                Ok(max_norm) => Some(max_norm),
+                Err(_) => return Err(entries.error("Saved file has a missing or invalid max-norm."))
+            }
+        };
+
+        let regularisation = Regularisation { l1, l2, biases, max_norm };
+        match regularisation.validate() {
+            Ok(()) => Ok(regularisation),
+            Err(_) => Err(entries.error(format!("Checkpoint has invalid regularisation with L1 and L2 penalties of {} and {} and a max-norm of {:?}.", l1, l2, max_norm)))
+        }
+    }
+
+    /// Returns whether the penalties apply to parameters of the specified kind.
+    fn applies_to(&self, kind : ParameterKind) -> bool {
+        match kind {
+            ParameterKind::Weights => true,
+            ParameterKind::Biases => self.biases,
+        }
+    }
+
+    /// Calculates the total penalty for the parameters of the layers.
+    pub (crate) fn penalty<T : Float>(&self, layers : &[Box<dyn Layer<T>>]) -> f64 {
+        if self.l1 == 0.0 && self.l2 == 0.0 {
+            return 0.0
+        }
+
+        layers
+        .iter()
+        .flat_map(|layer| layer.parameters().into_iter().zip(layer.parameter_kinds()))
+        .filter(|(_, kind)| self.applies_to(*kind))
+        .flat_map(|(parameters, _)| parameters.iter())
+        .map(|parameter| {
+            let parameter = parameter.to_f64();
+            self.l1 * parameter.abs() + 0.5 * self.l2 * parameter * parameter
+        })
+        .sum()
+    }
+
+    /// Adds the gradient of the penalties for a set of parameters of the specified kind to the
+    /// gradient of the cost with respect to them.
+    pub (crate) fn add_gradient<T : Float>(&self, kind : ParameterKind, parameters : &[T], gradient : &mut [T]) {
+        if (self.l1 == 0.0 && self.l2 == 0.0) || !self.applies_to(kind) {
+            return
+        }
+
+        let (l1, l2) = (T::from_f64(self.l1), T::from_f64(self.l2));
+        for (parameter, gradient) in parameters.iter().zip(gradient.iter_mut()) {
+            // The L1 penalty has no derivative at zero, so it is taken to be zero there.
+            let sign = if *parameter == T::ZERO { T::ZERO } else { parameter.signum() };
+            *gradient += l1 * sign + l2 * *parameter;
+        }
+    }
+
+    /// Applies the max-norm constraint, if there is one, to the layers.
+    pub (crate) fn constrain<T : Float>(&self, layers : &mut [Box<dyn Layer<T>>]) {
+        if let Some(max_norm) = self.max_norm {
+            for layer in layers {
+                layer.constrain_norms(max_norm);
+            }
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use std::fmt;
+use std::fs;
+use std::path;
+use std::str;
+use std::str::SplitWhitespace;
+
+use crate::error::NetworkError;
+use crate::float::Float;
+use crate::{Network, layer, weights_gen};
+
+/// Identifies a file as a saved network.
+const HEADER : &str = "feedforward-network";
+
+/// The version of the format written by `Network::save`. This should be incremented whenever the
+/// format changes, so that old files are rejected rather than misread.
+const VERSION : u32 = 3;
+
+impl<T : Float> Network<T> {
+    /// Saves the network to a text file, recording each layer along with its parameters and any
+    /// state such as the running statistics of batch normalisation. Networks with custom layers or
+    /// activation functions cannot be saved. The file does not record the precision of the network,
+    /// so can be loaded into a network of either precision.
+    pub fn save(&self, path : path::PathBuf) -> Result<(), NetworkError> {
+        fs::write(path, self.serialise()?)?;
+        Ok(())
+    }
+
+    /// Loads a network previously written by `Network::save`. The random number generator used
+    /// during training is not saved, so the loaded network is given one seeded from system entropy,
+    /// which can be replaced with `with_rng`.
+    pub fn load(path : path::PathBuf) -> Result<Network<T>, NetworkError> {
+        let contents = fs::read_to_string(path)?;
+
+        Network::deserialise(&mut Lines::new(&contents))
+    }
+
+    /// Writes the network in the saved format.
+    pub (crate) fn serialise(&self) -> Result<String, NetworkError> {
+        let mut contents = format!("{} {}\nlayers {}\n", HEADER, VERSION, self.layers.len());
+
+        for layer in &self.layers {
+            contents.push_str(&layer.serialise()?);
+        }
+
+        Ok(contents)
+    }
+
+    /// Reads a network in the saved format from the provided lines.
+    pub (crate) fn deserialise(lines : &mut Lines) -> Result<Network<T>, NetworkError> {
+        let mut header = expect_line(lines, HEADER)?;
+        let version : u32 = parse(&mut header, "version")?;
+        if version != VERSION {
+            return Err(header.error(format!("Saved network has format version {}, but only version {} is supported.", version, VERSION)))
+        }
+
+        let mut count = expect_line(lines, "layers")?;
+        let num_layers : usize = parse(&mut count, "number of layers")?;
+        if num_layers == 0 {
+            return Err(count.error("Saved network has no layers."))
+        }
+
+        // The count is not trusted to size the allocation, as the file may be malformed.
+        let mut layers = Vec::new();
+        for _ in 0..num_layers {
+            layers.push(layer::deserialise(lines)?);
+        }
+
+        Network::from_layers(layers, weights_gen::from_entropy())
+    }
+}
+
+/// Joins the values into a single space separated string, written such that they are read back
+/// exactly.
+pub (crate) fn join<T : fmt::Display>(values : &[T]) -> String {
+    values
+    .iter()
+    .map(|x| x.to_string())
+    .collect::<Vec<String>>()
+    .join(" ")
+}
+
+/// The lines of a saved file, numbered so that errors can report where they occurred.
+pub (crate) struct Lines<'a> {
+    lines : str::Lines<'a>,
+    row : usize,
+}
+
+impl<'a> Lines<'a> {
+    pub (crate) fn new(contents : &'a str) -> Lines<'a> {
+        Lines { lines : contents.lines(), row : 0 }
+    }
+
+    /// Reads the next line, returning its label and the remaining entries on the line.
+    pub (crate) fn next_labelled(&mut self, description : &str) -> Result<(&'a str, Entries<'a>), NetworkError> {
+        self.row += 1;
+        let line = match self.lines.next() {
+            Some(line) => line,
+            None => return Err(NetworkError::parse(self.row, 0, format!("Saved file ended early, expected {}.", description)))
+        };
+
+        let mut entries = Entries { entries : line.split_whitespace(), row : self.row, column : 1 };
+        match entries.entries.next() {
+            Some(label) => Ok((label, entries)),
+            None => Err(entries.error(format!("Saved file is malformed, expected {}.", description)))
+        }
+    }
+}
+
+/// The entries on a line of a saved file, after its label.
+pub (crate) struct Entries<'a> {
+    entries : SplitWhitespace<'a>,
+    row : usize,
+    column : usize,
+}
+
+impl Entries<'_> {
+    /// Creates a parse error at the last entry read from the line.
+    pub (crate) fn error(&self, message : impl Into<String>) -> NetworkError {
+        NetworkError::parse(self.row, self.column, message)
+    }
+}
+
+/// Reads the next line, checking that it starts with the expected label, and returns the
+/// remaining entries on the line.
+pub (crate) fn expect_line<'a>(lines : &mut Lines<'a>, label : &str) -> Result<Entries<'a>, NetworkError> {
+    match lines.next_labelled(label)? {
+        (found, entries) if found == label => Ok(entries),
+        (_, entries) => Err(entries.error(format!("Saved file is malformed, expected {}.", label)))
+    }
+}
+
+/// Parses the next entry on a line.
+pub (crate) fn parse<T : str::FromStr>(entries : &mut Entries, description : &str) -> Result<T, NetworkError> {
+    entries.column += 1;
+    match entries.entries.next().map(|entry| entry.parse::<T>()) {
+        Some(Ok(value)) => Ok(value),
+        _ => Err(entries.error(format!("Saved file has a missing or invalid {}.", description)))
+    }
+}
+
+/// Parses all the entries on a line, checking that there are the expected number of them.
+pub (crate) fn parse_all<T : str::FromStr>(entries : Entries, expected : usize, description : &str) -> Result<Vec<T>, NetworkError> {
+    let row = entries.row;
+    let values = parse_values(entries, description)?;
+
+    if values.len() != expected {
+        return Err(NetworkError::shape_mismatch(format!("the number of {} on line {} of the saved file", description, row), expected, values.len()))
+    }
+
+    Ok(values)
+}
+
+/// Splits a name followed by an optional parameter in brackets, such as `leaky_relu(0.01)`, as
+/// written by the `Display` implementations of `Activation` and `Initialiser`.
+pub (crate) fn split_parameter<'a>(string : &'a str, description : &str) -> Result<(&'a str, Option<f64>), NetworkError> {
+    match string.split_once('(') {
+        Some((name, rest)) => match rest.strip_suffix(')') {
+            Some(parameter) => match parameter.parse::<f64>() {
+                Ok(parameter) => Ok((name, Some(parameter))),
+                Err(_) => Err(NetworkError::invalid_config(format!("Invalid parameter for {} {}.", description, string)))
+            },
+            None => Err(NetworkError::invalid_config(format!("Invalid {} {}.", description, string)))
+        },
+        None => Ok((string, None))
+    }
+}
+
+/// Parses all the entries on a line as values.
+pub (crate) fn parse_values<T : str::FromStr>(mut entries : Entries, description : &str) -> Result<Vec<T>, NetworkError> {
+    let mut values = Vec::new();
+    while let Some(entry) = entries.entries.next() {
+        entries.column += 1;
+        match entry.parse::<T>() {
+            Ok(value) => values.push(value),
+            Err(_) => return Err(entries.error(format!("Saved file has an invalid value {} in its {}.", entry, description)))
+        }
+    }
+
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::Lines;
+    use crate::{DataSet, Network};
+    use crate::activation::Activation;
+    use crate::algebra::Vector;
+    use crate::error::NetworkError;
+    use crate::loss::MeanSquaredError;
+    use crate::optimizer::Sgd;
+
+    fn data_set(quantity : usize, entries : usize, offset : f64) -> DataSet {
+        DataSet(
+            (0..quantity)
+            .map(|set| Vector::new((0..entries).map(|entry| ((set * entries + entry) as f64 + offset).cos()).collect()))
+            .collect()
+        )
+    }
+
+    #[test]
+    fn loads_saved_network() {
+        let mut network =
+            Network::builder()
+            .input(3)
+            .dense(5, Activation::LeakyRelu(0.1))
+            .batch_norm()
+            .dropout(0.2)
+            .layer_norm()
+            .dense(2, Activation::Softmax)
+            .build(3)
+            .unwrap();
+        // Training moves the parameters and running statistics away from their initial values.
+        let (input, expected) = (data_set(12, 3, 0.0), data_set(12, 2, 1.0));
+        network.train_batch(&mut Sgd::new(0.1).unwrap(), &MeanSquaredError, 4, &input, &expected).unwrap();
+
+        let path = env::temp_dir().join(format!("network-save-test-{}.txt", process::id()));
+        network.save(path.clone()).unwrap();
+        let loaded : Network = Network::load(path.clone()).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(network.serialise().unwrap(), loaded.serialise().unwrap());
+        let (outputs, loaded_outputs) = (network.test(&input).unwrap(), loaded.test(&input).unwrap());
+        for set in 0..input.quantity() {
+            assert_eq!(outputs.get(set), loaded_outputs.get(set));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_initialiser() {
+        let saved = "feedforward-network 3\nlayers 1\ndense 1 1 truncated_normal(-1) constant(NaN)\nweights 0.5\nbiases 0\n";
+        let loaded : Result<Network, NetworkError> = Network::deserialise(&mut Lines::new(saved));
+
+        assert!(matches!(loaded, Err(NetworkError::InvalidConfig(_))));
+    }
+}
@@ -0,0 +1,408 @@
+//! Learning rate schedules, which vary the learning rate of an optimizer as training progresses.
+
+use std::f64::consts;
+use std::fmt;
+
+use crate::error::NetworkError;
+use crate::float::Float;
+use crate::optimizer::Optimizer;
+
+/// Calculates the learning rate from a base learning rate and how far training has progressed,
+/// counted in either steps (mini-batches) or epochs from zero depending on how the schedule is
+/// used by its `Scheduler`.
+pub trait LrSchedule : fmt::Debug {
+    /// Returns the learning rate for step or epoch `t`.
+    fn learning_rate(&self, base_rate : f64, t : usize) -> f64;
+
+    /// Records the cost on a validation set at the end of an epoch. Only schedules which adapt to
+    /// the cost, such as `ReduceOnPlateau`, make use of it.
+    fn observe(&mut self, _cost : f64) {}
+
+    /// Returns the internal state of the schedule, so that it can be saved in a checkpoint.
+    fn state(&self) -> Vec<f64> {
+        Vec::new()
+    }
+
+    /// Checks that state could be loaded by `load_state`, without loading it. Schedules with state
+    /// must override this along with `state` and `load_state`.
+    fn validate_state(&self, state : &[f64]) -> Result<(), NetworkError> {
+        if state.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(NetworkError::invalid_config("Attempt to load state into a schedule which does not have any."))
+        }
+    }
+
+    /// Restores internal state previously returned by `state`.
+    fn load_state(&mut self, state : Vec<f64>) -> Result<(), NetworkError> {
+        self.validate_state(&state)
+    }
+}
+
+/// Whether a schedule is advanced after each step (mini-batch) or after each epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interval {
+    Step,
+    Epoch,
+}
+
+/// Applies a schedule to an optimizer while training with `Network::train_batch_scheduled`, which
+/// sets the learning rate before each step and counts the steps and epochs completed.
+#[derive(Debug)]
+pub struct Scheduler {
+    schedule : Box<dyn LrSchedule>,
+    base_rate : f64,
+    interval : Interval,
+    epoch : usize,
+    step : usize,
+}
+
+impl Scheduler {
+    /// Creates a scheduler which applies the schedule to the base learning rate, advancing it at
+    /// the specified interval.
+    pub fn new(schedule : Box<dyn LrSchedule>, base_rate : f64, interval : Interval) -> Scheduler {
+        Scheduler {
+            schedule,
+            base_rate,
+            interval,
+            epoch : 0,
+            step : 0,
+        }
+    }
+
+    /// Sets the number of epochs and steps already completed. `Checkpoint::resume_scheduled` sets
+    /// them when resuming from a checkpoint, along with the history of schedules which adapt to the
+    /// cost.
+    pub fn with_position(mut self, epoch : usize, step : usize) -> Scheduler {
+        self.set_position(epoch, step);
+        self
+    }
+
+    /// Returns the learning rate for the next step.
+    pub fn learning_rate(&self) -> f64 {
+        let t = match self.interval {
+            Interval::Step => self.step,
+            Interval::Epoch => self.epoch,
+        };
+
+        self.schedule.learning_rate(self.base_rate, t)
+    }
+
+    /// Records the cost on a validation set at the end of an epoch, for schedules which adapt to
+    /// it.
+    pub fn observe(&mut self, cost : f64) {
+        self.schedule.observe(cost);
+    }
+
+    /// Returns the internal state of the schedule, so that it can be saved in a checkpoint.
+    pub fn state(&self) -> Vec<f64> {
+        self.schedule.state()
+    }
+
+    /// Restores internal state of the schedule previously returned by `state`.
+    pub fn load_state(&mut self, state : Vec<f64>) -> Result<(), NetworkError> {
+        self.schedule.load_state(state)
+    }
+
+    /// Checks that state could be loaded by `load_state`, without loading it.
+    pub fn validate_state(&self, state : &[f64]) -> Result<(), NetworkError> {
+        self.schedule.validate_state(state)
+    }
+
+    /// Sets the number of epochs and steps already completed.
+    pub (crate) fn set_position(&mut self, epoch : usize, step : usize) {
+        self.epoch = epoch;
+        self.step = step;
+    }
+
+    /// Sets the learning rate of the optimizer for the next step.
+    pub (crate) fn apply<T : Float>(&self, optimizer : &mut dyn Optimizer<T>) {
+        optimizer.set_learning_rate(self.learning_rate());
+    }
+
+    /// Records that a step has been completed.
+    pub (crate) fn end_step(&mut self) {
+        self.step += 1;
+    }
+
+    /// Records that an epoch has been completed.
+    pub (crate) fn end_epoch(&mut self) {
+        self.epoch += 1;
+    }
+}
+
+/// Interpolates from `start` to `end` along half a cosine, as `fraction` goes from zero to one.
+fn cosine_interpolate(start : f64, end : f64, fraction : f64) -> f64 {
+    end + (start - end) * (1.0 + (consts::PI * fraction).cos()) / 2.0
+}
+
+/// Keeps the learning rate at its base value, which is mostly useful after a `LinearWarmup`.
+#[derive(Debug, Clone)]
+pub struct Constant;
+
+impl LrSchedule for Constant {
+    fn learning_rate(&self, base_rate : f64, _t : usize) -> f64 {
+        base_rate
+    }
+}
+
+/// Multiplies the learning rate by `gamma` every `step_size` steps or epochs.
+#[derive(Debug, Clone)]
+pub struct StepDecay {
+    step_size : usize,
+    gamma : f64,
+}
+
+impl StepDecay {
+    pub fn new(step_size : usize, gamma : f64) -> Result<StepDecay, NetworkError> {
+        if step_size == 0 {
+            return Err(NetworkError::invalid_config("Attempt to use a step decay schedule with a step size of zero."))
+        }
+        if gamma.is_nan() || gamma <= 0.0 {
+            return Err(NetworkError::invalid_config(format!("Attempt to use a step decay schedule with a gamma of {}, which is not positive.", gamma)))
+        }
+
+        Ok(StepDecay { step_size, gamma })
+    }
+}
+
+impl LrSchedule for StepDecay {
+    fn learning_rate(&self, base_rate : f64, t : usize) -> f64 {
+        base_rate * self.gamma.powf((t / self.step_size) as f64)
+    }
+}
+
+/// Multiplies the learning rate by `gamma` every step or epoch.
+#[derive(Debug, Clone)]
+pub struct ExponentialDecay {
+    gamma : f64,
+}
+
+impl ExponentialDecay {
+    pub fn new(gamma : f64) -> Result<ExponentialDecay, NetworkError> {
+        if gamma.is_nan() || gamma <= 0.0 {
+            return Err(NetworkError::invalid_config(format!("Attempt to use an exponential decay schedule with a gamma of {}, which is not positive.", gamma)))
+        }
+
+        Ok(ExponentialDecay { gamma })
+    }
+}
+
+impl LrSchedule for ExponentialDecay {
+    fn learning_rate(&self, base_rate : f64, t : usize) -> f64 {
+        base_rate * self.gamma.powf(t as f64)
+    }
+}
+
+/// Cosine annealing with warm restarts (SGDR), which anneals the learning rate from its base value
+/// to `min_rate` along half a cosine and then restarts. The first cycle lasts `period` steps or
+/// epochs, and each cycle is `multiplier` times as long as the one before.
+#[derive(Debug, Clone)]
+pub struct CosineWarmRestarts {
+    period : usize,
+    multiplier : usize,
+    min_rate : f64,
+}
+
+impl CosineWarmRestarts {
+    pub fn new(period : usize, multiplier : usize, min_rate : f64) -> Result<CosineWarmRestarts, NetworkError> {
+        if period == 0 || multiplier == 0 {
+            return Err(NetworkError::invalid_config("Attempt to use a cosine annealing schedule with a period or multiplier of zero."))
+        }
+        if !(0.0..).contains(&min_rate) {
+            return Err(NetworkError::invalid_config(format!("Attempt to use a cosine annealing schedule with a minimum rate of {}, which is negative.", min_rate)))
+        }
+
+        Ok(CosineWarmRestarts { period, multiplier, min_rate })
+    }
+}
+
+impl LrSchedule for CosineWarmRestarts {
+    fn learning_rate(&self, base_rate : f64, t : usize) -> f64 {
+        let (mut position, mut period) = (t, self.period);
+        if self.multiplier == 1 {
+            position %= period;
+        }
+        else {
+            while position >= period {
+                position -= period;
+                period *= self.multiplier;
+            }
+        }
+
+        cosine_interpolate(base_rate, self.min_rate, position as f64 / period as f64)
+    }
+}
+
+/// The one-cycle policy, which over `total` steps or epochs raises the learning rate from the base
+/// rate divided by `initial_divisor` up to the base rate, for the fraction `warmup` of them, and
+/// then anneals it down to the initial rate divided by `final_divisor`, both along half a cosine.
+/// The rate stays at its final value after `total`.
+#[derive(Debug, Clone)]
+pub struct OneCycle {
+    total : usize,
+    warmup : f64,
+    initial_divisor : f64,
+    final_divisor : f64,
+}
+
+impl OneCycle {
+    pub fn new(total : usize, warmup : f64, initial_divisor : f64, final_divisor : f64) -> Result<OneCycle, NetworkError> {
+        if total == 0 {
+            return Err(NetworkError::invalid_config("Attempt to use a one-cycle schedule over zero steps."))
+        }
+        if !(0.0..1.0).contains(&warmup) {
+            return Err(NetworkError::invalid_config(format!("Attempt to use a one-cycle schedule with a warmup fraction of {}, which is not at least zero and less than one.", warmup)))
+        }
+        if initial_divisor.is_nan() || initial_divisor <= 0.0 || final_divisor.is_nan() || final_divisor <= 0.0 {
+            return Err(NetworkError::invalid_config(format!("Attempt to use a one-cycle schedule with divisors of {} and {}, which must be positive.", initial_divisor, final_divisor)))
+        }
+
+        Ok(OneCycle { total, warmup, initial_divisor, final_divisor })
+    }
+}
+
+impl LrSchedule for OneCycle {
+    fn learning_rate(&self, base_rate : f64, t : usize) -> f64 {
+        let initial_rate = base_rate / self.initial_divisor;
+        let final_rate = initial_rate / self.final_divisor;
+        let warmup_end = (self.warmup * self.total as f64).round() as usize;
+
+        if t < warmup_end {
+            cosine_interpolate(initial_rate, base_rate, t as f64 / warmup_end as f64)
+        }
+        else if t < self.total {
+            cosine_interpolate(base_rate, final_rate, (t - warmup_end) as f64 / (self.total - warmup_end) as f64)
+        }
+        else {
+            final_rate
+        }
+    }
+}
+
+/// Raises the learning rate linearly from a small value up to the base rate over the first
+/// `warmup` steps or epochs, and then follows another schedule, counting from the end of the
+/// warmup.
+#[derive(Debug)]
+pub struct LinearWarmup {
+    warmup : usize,
+    then : Box<dyn LrSchedule>,
+}
+
+impl LinearWarmup {
+    pub fn new(warmup : usize, then : Box<dyn LrSchedule>) -> Result<LinearWarmup, NetworkError> {
+        if warmup == 0 {
+            return Err(NetworkError::invalid_config("Attempt to use a linear warmup schedule with a warmup of zero."))
+        }
+
+        Ok(LinearWarmup { warmup, then })
+    }
+}
+
+impl LrSchedule for LinearWarmup {
+    fn learning_rate(&self, base_rate : f64, t : usize) -> f64 {
+        if t < self.warmup {
+            base_rate * (t + 1) as f64 / self.warmup as f64
+        }
+        else {
+            self.then.learning_rate(base_rate, t - self.warmup)
+        }
+    }
+
+    fn observe(&mut self, cost : f64) {
+        self.then.observe(cost);
+    }
+
+    fn state(&self) -> Vec<f64> {
+        self.then.state()
+    }
+
+    fn validate_state(&self, state : &[f64]) -> Result<(), NetworkError> {
+        self.then.validate_state(state)
+    }
+
+    fn load_state(&mut self, state : Vec<f64>) -> Result<(), NetworkError> {
+        self.then.load_state(state)
+    }
+}
+
+/// Multiplies the learning rate by `factor` whenever the validation cost passed to `observe` has
+/// not improved on the best so far for more than `patience` epochs in a row, down to at least
+/// `min_rate`.
+#[derive(Debug, Clone)]
+pub struct ReduceOnPlateau {
+    factor : f64,
+    patience : usize,
+    min_rate : f64,
+    best : f64,
+    epochs_without_improvement : usize,
+    scale : f64,
+}
+
+impl ReduceOnPlateau {
+    pub fn new(factor : f64, patience : usize, min_rate : f64) -> Result<ReduceOnPlateau, NetworkError> {
+        if factor.is_nan() || factor <= 0.0 || factor >= 1.0 {
+            return Err(NetworkError::invalid_config(format!("Attempt to use a reduce on plateau schedule with a factor of {}, which is not between zero and one.", factor)))
+        }
+        if !(0.0..).contains(&min_rate) {
+            return Err(NetworkError::invalid_config(format!("Attempt to use a reduce on plateau schedule with a minimum rate of {}, which is negative.", min_rate)))
+        }
+
+        Ok(ReduceOnPlateau {
+            factor,
+            patience,
+            min_rate,
+            best : f64::INFINITY,
+            epochs_without_improvement : 0,
+            scale : 1.0,
+        })
+    }
+}
+
+impl LrSchedule for ReduceOnPlateau {
+    fn learning_rate(&self, base_rate : f64, _t : usize) -> f64 {
+        f64::max(base_rate * self.scale, self.min_rate)
+    }
+
+    fn observe(&mut self, cost : f64) {
+        if cost < self.best {
+            self.best = cost;
+            self.epochs_without_improvement = 0;
+        }
+        else {
+            self.epochs_without_improvement += 1;
+            if self.epochs_without_improvement > self.patience {
+                self.scale *= self.factor;
+                self.epochs_without_improvement = 0;
+            }
+        }
+    }
+
+    /// The state is the best cost so far, the number of epochs since it improved and the scale
+    /// applied to the base learning rate.
+    fn state(&self) -> Vec<f64> {
+        vec![self.best, self.epochs_without_improvement as f64, self.scale]
+    }
+
+    fn validate_state(&self, state : &[f64]) -> Result<(), NetworkError> {
+        let valid = match state[..] {
+            [best, epochs, scale] => !best.is_nan() && epochs >= 0.0 && epochs.fract() == 0.0 && scale > 0.0 && scale <= 1.0,
+            _ => false
+        };
+        if !valid {
+            return Err(NetworkError::invalid_config("Attempt to load malformed state into a reduce on plateau schedule."))
+        }
+
+        Ok(())
+    }
+
+    fn load_state(&mut self, state : Vec<f64>) -> Result<(), NetworkError> {
+        self.validate_state(&state)?;
+
+        self.best = state[0];
+        self.epochs_without_improvement = state[1] as usize;
+        self.scale = state[2];
+        Ok(())
+    }
+}
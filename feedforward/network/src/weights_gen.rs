@@ -1,28 +1,281 @@
+use std::fmt;
+use std::str;
+
 use rand::prelude::*;
+pub use rand_chacha::ChaCha8Rng;
+
+use crate::error::NetworkError;
+use crate::float::Float;
+use crate::save::split_parameter;
+
+/// Creates a random number generator from a seed, so that runs can be reproduced.
+pub fn seeded(seed : u64) -> ChaCha8Rng {
+    ChaCha8Rng::seed_from_u64(seed)
+}
+
+/// Creates a random number generator from system entropy.
+pub fn from_entropy() -> ChaCha8Rng {
+    ChaCha8Rng::from_entropy()
+}
+
+/// Captures the position of the random number generator as its seed, stream and word position.
+pub (crate) fn state(rng : &ChaCha8Rng) -> ([u8; 32], u64, u128) {
+    (rng.get_seed(), rng.get_stream(), rng.get_word_pos())
+}
+
+/// Recreates a random number generator at a position previously captured by `state`.
+pub (crate) fn restore(seed : [u8; 32], stream : u64, word_pos : u128) -> ChaCha8Rng {
+    let mut rng = ChaCha8Rng::from_seed(seed);
+    rng.set_stream(stream);
+    rng.set_word_pos(word_pos);
+    rng
+}
+
+fn uniform(rng : &mut ChaCha8Rng) -> f64 {
+    rng.gen()
+}
+
+/// Generates a uniform random variable between `-limit` and `limit`.
+fn symmetric_uniform(limit : f64, rng : &mut ChaCha8Rng) -> f64 {
+    (2.0 * uniform(rng) - 1.0) * limit
+}
+
+/// Shuffles the provided values into a uniformly random order.
+pub (crate) fn shuffle<T>(values : &mut [T], rng : &mut ChaCha8Rng) {
+    values.shuffle(rng);
+}
+
+/// Creates independent random number generators for `count` threads, seeded from the generator, so
+/// that work split between threads is reproducible for a given number of threads.
+pub (crate) fn split(rng : &mut ChaCha8Rng, count : usize) -> Vec<ChaCha8Rng> {
+    let seed : [u8; 32] = rng.gen();
+
+    (0..count)
+    .map(|stream| {
+        let mut thread_rng = ChaCha8Rng::from_seed(seed);
+        thread_rng.set_stream(stream as u64);
+        thread_rng
+    })
+    .collect()
+}
 
-static mut RNG : Option<ThreadRng> = None;
+/// Generates a mask for inverted dropout, where each entry is zero with probability `rate` and
+/// otherwise scales the value it is applied to by `1 / (1 - rate)`, so the expected value of the
+/// result is unchanged.
+pub (crate) fn dropout_mask<T : Float>(len : usize, rate : f64, rng : &mut ChaCha8Rng) -> Vec<T> {
+    let scale = T::from_f64(1.0 / (1.0 - rate));
+
+    (0..len)
+    .map(|_| if uniform(rng) < rate { T::ZERO } else { scale })
+    .collect()
+}
+
+/// Generates a standard normal random variable with the Box-Muller transform. The first uniform
+/// variable is taken from one so that it is never zero.
+fn normal_variable(rng : &mut ChaCha8Rng) -> f64 {
+    f64::sqrt(-2.0 * f64::ln(1.0 - uniform(rng))) * f64::cos(2.0 * std::f64::consts::PI * uniform(rng))
+}
 
-pub fn init() {
-    unsafe {
-        RNG = Some(rand::thread_rng());
+/// Generates a standard normal random variable, redrawing any more than two standard deviations
+/// from zero.
+fn truncated_normal_variable(rng : &mut ChaCha8Rng) -> f64 {
+    loop {
+        let value = normal_variable(rng);
+        if value.abs() <= 2.0 {
+            return value
+        }
     }
 }
 
-fn uniform() -> f64 {
-    unsafe {
-        match &mut RNG {
-            None => panic!("Cannot generate a random number without first calling init()."),
-            Some(rng) => rng.gen(),
+/// Generates a `rows`x`cols` matrix in row-major order whose rows are orthonormal, or whose columns
+/// are if there are more rows than columns, by applying the Gram-Schmidt process to normal random
+/// vectors.
+fn orthogonal(rows : usize, cols : usize, rng : &mut ChaCha8Rng) -> Vec<f64> {
+    let (count, len) = if rows <= cols { (rows, cols) } else { (cols, rows) };
+
+    let mut vectors : Vec<Vec<f64>> = Vec::with_capacity(count);
+    for _ in 0..count {
+        let mut vector : Vec<f64> = (0..len).map(|_| normal_variable(rng)).collect();
+
+        for previous in &vectors {
+            let projection : f64 = vector.iter().zip(previous.iter()).map(|(a, b)| a * b).sum();
+            for (a, b) in vector.iter_mut().zip(previous.iter()) {
+                *a -= projection * b;
+            }
         }
+
+        let norm = vector.iter().map(|a| a * a).sum::<f64>().sqrt();
+        for a in vector.iter_mut() {
+            *a /= norm;
+        }
+
+        vectors.push(vector);
+    }
+
+    if rows <= cols {
+        vectors.concat()
+    }
+    else {
+        (0..rows)
+        .flat_map(|row| vectors.iter().map(move |vector| vector[row]))
+        .collect()
     }
 }
 
-fn normal_variable() -> f64 {
-    f64::sqrt(-2.0 * f64::ln(uniform())) * f64::cos(2.0 * std::f64::consts::PI * uniform())
+/// A scheme for initialising the weights or biases of a layer, identified by name so that it can be
+/// recorded in saved networks. The scaled schemes use the number of inputs (fan in) and outputs
+/// (fan out) of the layer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Initialiser {
+    /// Xavier (or Glorot) uniform, between plus and minus `sqrt(6 / (fan_in + fan_out))`, which
+    /// suits tanh and sigmoid layers.
+    XavierUniform,
+    /// Xavier (or Glorot) normal, with standard deviation `sqrt(2 / (fan_in + fan_out))`.
+    XavierNormal,
+    /// He (or Kaiming) uniform, between plus and minus `sqrt(6 / fan_in)`, which suits relu layers.
+    HeUniform,
+    /// He (or Kaiming) normal, with standard deviation `sqrt(2 / fan_in)`.
+    HeNormal,
+    /// LeCun uniform, between plus and minus `sqrt(3 / fan_in)`, which suits selu layers.
+    LecunUniform,
+    /// LeCun normal, with standard deviation `sqrt(1 / fan_in)`.
+    LecunNormal,
+    /// A random matrix with orthonormal rows (or columns, if it has more rows than columns).
+    Orthogonal,
+    /// Normal with the specified standard deviation, redrawing values more than two standard
+    /// deviations from zero.
+    TruncatedNormal(f64),
+    Zeros,
+    Constant(f64),
 }
 
-/// Generates a normal random variable with the standard deviation determined by the left of the
-/// left and right layers.
-pub fn normal(left : usize, right : usize) -> f64 {
-    normal_variable() * f64::sqrt(2.0/((left + right) as f64))
+impl Initialiser {
+    /// Checks that the standard deviation of a truncated normal initialiser is not negative, and
+    /// that the value of a constant initialiser is a number.
+    pub (crate) fn validate(&self) -> Result<(), NetworkError> {
+        match self {
+            Initialiser::TruncatedNormal(std_dev) if !(0.0..).contains(std_dev) =>
+                Err(NetworkError::invalid_config(format!("Attempt to use a truncated normal initialiser with a standard deviation of {}, which must not be negative.", std_dev))),
+            Initialiser::Constant(value) if value.is_nan() =>
+                Err(NetworkError::invalid_config("Attempt to use a constant initialiser whose value is not a number.")),
+            _ => Ok(())
+        }
+    }
+
+    /// Generates the initial values of a `rows`x`cols` matrix in row-major order, for a layer with
+    /// the specified number of inputs and outputs.
+    pub (crate) fn generate<T : Float>(&self, rows : usize, cols : usize, fan_in : usize, fan_out : usize, rng : &mut ChaCha8Rng) -> Vec<T> {
+        let len = rows * cols;
+        let (fan_in, fan_out) = (fan_in as f64, fan_out as f64);
+
+        let values : Vec<f64> = match self {
+            Initialiser::XavierUniform => {
+                let limit = f64::sqrt(6.0 / (fan_in + fan_out));
+                (0..len).map(|_| symmetric_uniform(limit, rng)).collect()
+            },
+            Initialiser::XavierNormal => {
+                let std_dev = f64::sqrt(2.0 / (fan_in + fan_out));
+                (0..len).map(|_| normal_variable(rng) * std_dev).collect()
+            },
+            Initialiser::HeUniform => {
+                let limit = f64::sqrt(6.0 / fan_in);
+                (0..len).map(|_| symmetric_uniform(limit, rng)).collect()
+            },
+            Initialiser::HeNormal => {
+                let std_dev = f64::sqrt(2.0 / fan_in);
+                (0..len).map(|_| normal_variable(rng) * std_dev).collect()
+            },
+            Initialiser::LecunUniform => {
+                let limit = f64::sqrt(3.0 / fan_in);
+                (0..len).map(|_| symmetric_uniform(limit, rng)).collect()
+            },
+            Initialiser::LecunNormal => {
+                let std_dev = f64::sqrt(1.0 / fan_in);
+                (0..len).map(|_| normal_variable(rng) * std_dev).collect()
+            },
+            Initialiser::Orthogonal => orthogonal(rows, cols, rng),
+            Initialiser::TruncatedNormal(std_dev) => (0..len).map(|_| truncated_normal_variable(rng) * std_dev).collect(),
+            Initialiser::Zeros => vec![0.0; len],
+            Initialiser::Constant(value) => vec![*value; len],
+        };
+
+        values.into_iter().map(T::from_f64).collect()
+    }
+
+    /// Returns the name of the initialiser.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Initialiser::XavierUniform => "xavier_uniform",
+            Initialiser::XavierNormal => "xavier_normal",
+            Initialiser::HeUniform => "he_uniform",
+            Initialiser::HeNormal => "he_normal",
+            Initialiser::LecunUniform => "lecun_uniform",
+            Initialiser::LecunNormal => "lecun_normal",
+            Initialiser::Orthogonal => "orthogonal",
+            Initialiser::TruncatedNormal(_) => "truncated_normal",
+            Initialiser::Zeros => "zeros",
+            Initialiser::Constant(_) => "constant",
+        }
+    }
+}
+
+impl fmt::Display for Initialiser {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Initialiser::TruncatedNormal(parameter) | Initialiser::Constant(parameter) => write!(f, "{}({})", self.name(), parameter),
+            _ => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+/// Parses an initialiser from the format written by `Display`.
+impl str::FromStr for Initialiser {
+    type Err = NetworkError;
+
+    fn from_str(string : &str) -> Result<Initialiser, NetworkError> {
+        let (name, parameter) = split_parameter(string, "initialiser")?;
+
+        match (name, parameter) {
+            ("xavier_uniform", None) => Ok(Initialiser::XavierUniform),
+            ("xavier_normal", None) => Ok(Initialiser::XavierNormal),
+            ("he_uniform", None) => Ok(Initialiser::HeUniform),
+            ("he_normal", None) => Ok(Initialiser::HeNormal),
+            ("lecun_uniform", None) => Ok(Initialiser::LecunUniform),
+            ("lecun_normal", None) => Ok(Initialiser::LecunNormal),
+            ("orthogonal", None) => Ok(Initialiser::Orthogonal),
+            ("truncated_normal", Some(std_dev)) => Ok(Initialiser::TruncatedNormal(std_dev)),
+            ("zeros", None) => Ok(Initialiser::Zeros),
+            ("constant", Some(value)) => Ok(Initialiser::Constant(value)),
+            _ => Err(NetworkError::invalid_config(format!("Unknown initialiser {}.", string)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Initialiser;
+    use crate::Network;
+    use crate::activation::Activation;
+    use crate::error::NetworkError;
+
+    fn build(weights_init : Initialiser, biases_init : Initialiser) -> Result<Network, NetworkError> {
+        Network::builder()
+        .input(2)
+        .initialisers(weights_init, biases_init)
+        .dense(3, Activation::Tanh)
+        .build(0)
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        for (weights_init, biases_init) in [
+            (Initialiser::TruncatedNormal(-0.1), Initialiser::Zeros),
+            (Initialiser::TruncatedNormal(f64::NAN), Initialiser::Zeros),
+            (Initialiser::HeNormal, Initialiser::Constant(f64::NAN)),
+        ] {
+            assert!(matches!(build(weights_init, biases_init), Err(NetworkError::InvalidConfig(_))));
+        }
+
+        assert!(build(Initialiser::TruncatedNormal(0.0), Initialiser::Constant(-2.0)).is_ok());
+    }
 }
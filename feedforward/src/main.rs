@@ -1,9 +1,18 @@
 // TODO: Algebra module has new vector indexing functions, change all indexing over.
 
+use std::fs;
 use std::path;
+use std::thread;
 
 extern crate network;
-use network::{DataSet, activation, weights_gen, Network};
+use network::{DataSet, optimizer, loss};
+use network::activation::Activation;
+use network::builder::NetworkBuilder;
+use network::network::Mode;
+use network::weights_gen::Initialiser;
+use network::checkpoint::Checkpoint;
+use network::schedule::{Scheduler, Interval, OneCycle};
+use network::error::NetworkError;
 
 fn max_index(vector : &[f64]) -> usize {
     vector
@@ -14,32 +23,60 @@ fn max_index(vector : &[f64]) -> usize {
     .unwrap()
 }
 
-fn main() {
-    weights_gen::init();
+fn main() -> Result<(), NetworkError> {
+    let train_input : DataSet = DataSet::from_csv(path::PathBuf::from("../mnist-datasets/numberstraininput.csv"), false)?;
+    let train_expected = DataSet::from_csv(path::PathBuf::from("../mnist-datasets/numberstrainoutput.csv"), false)?;
+    let test_input = DataSet::from_csv(path::PathBuf::from("../mnist-datasets/numberstestinput.csv"), false)?;
+    let test_expected = DataSet::from_csv(path::PathBuf::from("../mnist-datasets/numberstestoutput.csv"), false)?;
     
-    let train_input = DataSet::from_csv(path::PathBuf::from("../mnist-datasets/numberstraininput.csv"), false).unwrap();
-    let train_expected = DataSet::from_csv(path::PathBuf::from("../mnist-datasets/numberstrainoutput.csv"), false).unwrap();
-    let test_input = DataSet::from_csv(path::PathBuf::from("../mnist-datasets/numberstestinput.csv"), false).unwrap();
-    let test_expected = DataSet::from_csv(path::PathBuf::from("../mnist-datasets/numberstestoutput.csv"), false).unwrap();
-    
-    let mut network =
-        Network::new(
-            vec![784, 20, 20, 10],
-            weights_gen::normal,
-            |_size| 0.1,
-            activation::swish,
-            activation::swish_derivative
-        );
-
-    let biases_lr = 0.1;
-    let weights_lr = 0.01;
-   
+    let learning_rate = 0.003;
+    let mut optimizer = optimizer::Adam::new(learning_rate)?;
+    let loss = loss::CategoricalCrossEntropy;
+    let batch_size = 32;
     let epochs = 5;
-    for _epoch in 0..epochs {
-        network.train_batch(weights_lr, biases_lr, &train_input, &train_expected)
+    let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+    // Initialisation and shuffling are seeded, so runs with the same number of threads are
+    // reproducible.
+    let seed = 0;
+
+    // The learning rate follows a single cycle over the whole run, peaking 30% of the way through.
+    let total_steps = epochs * train_input.quantity().div_ceil(batch_size);
+    let mut scheduler = Scheduler::new(Box::new(OneCycle::new(total_steps, 0.3, 25.0, 1e4)?), learning_rate, Interval::Step);
+
+    // Training continues from the last checkpoint if a previous run was interrupted, using the
+    // number of threads it was started with so that the results are unchanged. The checkpoint is
+    // removed once training finishes, so later runs start afresh.
+    let checkpoint_path = path::PathBuf::from("../checkpoint.txt");
+    let (mut network, first_epoch, mut step) =
+        if checkpoint_path.exists() {
+            Checkpoint::load(checkpoint_path.clone())?.resume_scheduled(&mut optimizer, &mut scheduler)?
+        }
+        else {
+            let network =
+                NetworkBuilder::new()
+                .initialisers(Initialiser::XavierNormal, Initialiser::Constant(0.1))
+                .input(784)
+                .dense(20, Activation::Swish)
+                .dense(20, Activation::Swish)
+                .dense(10, Activation::Softmax)
+                .threads(threads)
+                .build(seed)?;
+
+            (network, 0, 0)
+        };
+
+    for epoch in first_epoch..epochs {
+        step += network.train_batch_scheduled(&mut optimizer, &mut scheduler, &loss, batch_size, &train_input, &train_expected)?;
+        Checkpoint::new(&network, &optimizer, epoch + 1, step).with_scheduler(&scheduler).save(checkpoint_path.clone())?;
+    }
+
+    network.save(path::PathBuf::from("../trained-network.txt"))?;
+    if checkpoint_path.exists() {
+        fs::remove_file(&checkpoint_path)?;
     }
+    network.set_mode(Mode::Inference);
 
-    let testing_output = network.test(&test_input);
+    let testing_output = network.test(&test_input)?;
 
     let mut correct_count : u32 = 0;
     for i in 0..testing_output.quantity() {
@@ -59,9 +96,11 @@ fn main() {
 
 
     let cost_sum : f64 =
-        network.cost(&testing_output, &test_expected)
+        network.cost(&testing_output, &test_expected, &loss)?
         .iter()
         .sum();
 
     println!("{}", cost_sum / testing_output.quantity() as f64);
+
+    Ok(())
 }